@@ -3,13 +3,15 @@
 // Using the method described here: http://web.archive.org/web/20070610223835/http://www.teknikus.dk/tj/gdc2001.htm
 
 use macroquad::prelude::*;
-use macroquad::telemetry::frame;
-use core::num;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
 use std::ops;
-use std::time::{Duration, Instant};
 use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Vec3 {
     x: f32,
     y: f32,
@@ -79,12 +81,129 @@ impl Vec3 {
     fn length(&self) -> f32 {
         (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
     }
+
+    fn cross(&self, rhs: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+
+    // Component-wise 1 / x
+    fn recip(&self) -> Vec3 {
+        Vec3 {
+            x: 1.0 / self.x,
+            y: 1.0 / self.y,
+            z: 1.0 / self.z,
+        }
+    }
+
+    fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    // 1 / length, infinite for the zero vector
+    fn length_recip(&self) -> f32 {
+        1.0 / self.length()
+    }
+
+    // Returns the zero vector for zero-length input instead of NaNs.
+    fn normalized(&self) -> Vec3 {
+        let len_recip = self.length_recip();
+        if len_recip.is_infinite() {
+            return *self;
+        }
+
+        *self * len_recip
+    }
+}
+
+// Returns the cloth and particle index of the active particle closest to `point` over all cloths.
+fn nearest_in_cloths(cloths: &[Cloth], point: Vec3) -> Option<(usize, usize)> {
+    let mut nearest: Option<(usize, usize, f32)> = None;
+
+    for (c, cloth) in cloths.iter().enumerate() {
+        if let Some((p, d)) = nearest_particle(&cloth.pos, &cloth.active, point) {
+            if nearest.is_none_or(|(_, _, best)| d < best) {
+                nearest = Some((c, p, d));
+            }
+        }
+    }
+
+    nearest.map(|(c, p, _)| (c, p))
 }
 
 fn distance(a: Vec3, b: Vec3) -> f32 {
     (a - b).length()
 }
 
+// Returns the index of the active particle closest to `point` along with its distance.
+fn nearest_particle(pos: &[Vec3], active: &[bool], point: Vec3) -> Option<(usize, f32)> {
+    let mut nearest: Option<(usize, f32)> = None;
+
+    for p in 0..pos.len() {
+        if !active[p] {
+            continue;
+        }
+
+        let d = distance(point, pos[p]);
+        if nearest.is_none_or(|(_, best)| d < best) {
+            nearest = Some((p, d));
+        }
+    }
+
+    nearest
+}
+
+fn triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    (b - a).cross(c - a).length() * 0.5
+}
+
+// Total area of the cloth, with every grid cell split into two triangles.
+fn cloth_area(pos: &[Vec3], num_rows: usize, num_cols: usize) -> f32 {
+    let mut area = 0.0;
+
+    for row in 0..num_rows - 1 {
+        for col in 0..num_cols - 1 {
+            let top_left     = pos[row * num_cols + col];
+            let top_right    = pos[row * num_cols + col + 1];
+            let bottom_left  = pos[(row + 1) * num_cols + col];
+            let bottom_right = pos[(row + 1) * num_cols + col + 1];
+
+            area += triangle_area(top_left, top_right, bottom_left);
+            area += triangle_area(top_right, bottom_right, bottom_left);
+        }
+    }
+
+    area
+}
+
+// Area of cloth each particle stands for: a quarter of every grid cell it's a corner of.
+fn particle_areas(pos: &[Vec3], num_rows: usize, num_cols: usize) -> Vec<f32> {
+    let mut areas = vec![0.0; pos.len()];
+
+    for row in 0..num_rows - 1 {
+        for col in 0..num_cols - 1 {
+            let corners = [
+                row * num_cols + col,
+                row * num_cols + col + 1,
+                (row + 1) * num_cols + col,
+                (row + 1) * num_cols + col + 1,
+            ];
+
+            let area = triangle_area(pos[corners[0]], pos[corners[1]], pos[corners[2]])
+                + triangle_area(pos[corners[1]], pos[corners[3]], pos[corners[2]]);
+
+            for p in corners {
+                areas[p] += area / 4.0;
+            }
+        }
+    }
+
+    areas
+}
+
 fn vclamp(value: Vec3, min: Vec3, max: Vec3) -> Vec3 {
     Vec3 {
         x: clamp(value.x, min.x, max.x),
@@ -93,19 +212,13 @@ fn vclamp(value: Vec3, min: Vec3, max: Vec3) -> Vec3 {
     }
 }
 
-fn random_i32(min: i32, max: i32) -> i32 {
-    let span = (max - min).abs() as u32;
-
-    (rand::rand() % span) as i32 - (span as i32) / 2
-}
-
 fn random_f32(min: f32, max: f32) -> f32 {
-    union f32_u32 {
+    union F32U32 {
         f: f32,
         u: u32,
     }
     
-    let mut a = f32_u32 {u: (rand::rand() >> 9) | 0x3f800000};
+    let mut a = F32U32 {u: (rand::rand() >> 9) | 0x3f800000};
     
     unsafe {
         a.f -= 1.0;
@@ -118,188 +231,5716 @@ fn random_f32(min: f32, max: f32) -> f32 {
     }
 }
 
-#[derive(Clone, Copy)]
+// In deterministic mode the random number generator starts from `seed` so every run plays out the
+// same, otherwise it's seeded from the clock.
+fn seed_random(deterministic: bool, seed: u64) {
+    if deterministic {
+        rand::srand(seed);
+    } else {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        rand::srand(now.as_nanos() as u64);
+    }
+}
+
+// Switching into deterministic mode restarts the random number generator from the seed
+fn toggle_deterministic(config: &mut Config) {
+    config.deterministic = !config.deterministic;
+    seed_random(config.deterministic, config.seed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum BoundaryMode {
+    // Particles are stopped at the edges
+    Clamp,
+    // Particles leaving one edge re-enter at the opposite edge, keeping their velocity
+    Wrap,
+}
+
+impl BoundaryMode {
+    fn next(self) -> BoundaryMode {
+        match self {
+            BoundaryMode::Clamp => BoundaryMode::Wrap,
+            BoundaryMode::Wrap  => BoundaryMode::Clamp,
+        }
+    }
+}
+
+fn wrap_axis(pos: &mut f32, old_pos: &mut f32, min: f32, max: f32) {
+    let span = max - min;
+    if span <= 0.0 {
+        return;
+    }
+
+    // Shift both positions by the same amount so the implicit velocity is carried across
+    let shift = if *pos > max {
+        -span * ((*pos - min) / span).floor()
+    } else if *pos < min {
+        span * ((min - *pos) / span).ceil()
+    } else {
+        0.0
+    };
+
+    *pos += shift;
+    *old_pos += shift;
+}
+
+fn apply_boundary(mode: BoundaryMode, pos: &mut Vec3, old_pos: &mut Vec3, min: Vec3, max: Vec3) {
+    match mode {
+        // Only x and y, the window has no depth and a folded cloth has to keep its crease
+        BoundaryMode::Clamp => {
+            pos.x = clamp(pos.x, min.x, max.x);
+            pos.y = clamp(pos.y, min.y, max.y);
+        }
+        BoundaryMode::Wrap => {
+            wrap_axis(&mut pos.x, &mut old_pos.x, min.x, max.x);
+            wrap_axis(&mut pos.y, &mut old_pos.y, min.y, max.y);
+        }
+    }
+}
+
+// Region the particles are kept inside of when it's larger than the window, with the view
+// following the cloth around it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct WorldBounds {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl WorldBounds {
+    // Top left corner of a `screen_size` view centered on `focus` as far as the bounds allow.
+    // Bounds smaller than the view are aligned with its top left corner.
+    fn view_offset(&self, focus: Vec3, screen_size: Vec3) -> Vec3 {
+        Vec3 {
+            x: (focus.x - screen_size.x / 2.0).min(self.max.x - screen_size.x).max(self.min.x),
+            y: (focus.y - screen_size.y / 2.0).min(self.max.y - screen_size.y).max(self.min.y),
+            z: 0.0,
+        }
+    }
+}
+
+// Average position of the active particles of every cloth.
+fn centroid(cloths: &[Cloth]) -> Option<Vec3> {
+    let mut sum = Vec3::default();
+    let mut count = 0;
+
+    for cloth in cloths {
+        for p in 0..cloth.pos.len() {
+            if cloth.active[p] {
+                sum += cloth.pos[p];
+                count += 1;
+            }
+        }
+    }
+
+    (count > 0).then(|| sum * (1.0 / count as f32))
+}
+
+// Camera showing the `screen_size` part of the world with its top left corner at `view_offset`.
+// `from_display_rect` makes y grow upward when drawing to the screen, which would flip the cloth
+// compared to the default camera, so its zoom is negated to keep y growing downward.
+fn view_camera(view_offset: Vec3, screen_size: Vec3) -> Camera2D {
+    let mut camera = Camera2D::from_display_rect(Rect::new(view_offset.x, view_offset.y, screen_size.x, screen_size.y));
+    camera.zoom.y = camera.zoom.y.abs();
+    camera
+}
+
+// Cursor position in the world, given the top left corner of the view.
+fn mouse_world(view_offset: Vec3) -> Vec3 {
+    let mouse = mouse_position();
+    Vec3{x: mouse.0 + view_offset.x, y: mouse.1 + view_offset.y, z: 0.0}
+}
+
+// Horizontal floor that pieces torn off the cloth land on and keep simulating, rather than
+// piling up against the bottom of the window.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Floor {
+    // Height of the floor, y grows downward
+    y: f32,
+    // Fraction of the sliding velocity removed while a particle touches the floor
+    friction: f32,
+}
+
+impl Default for Floor {
+    fn default() -> Self {
+        Floor {
+            y: 550.0,
+            friction: 0.5,
+        }
+    }
+}
+
+// Shadow of the cloth squashed onto the ground, drawn beneath it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Shadow {
+    // Horizontal shift of the shadow, as if lit from the side
+    offset: f32,
+    // Height of the shadow over the height of the cloth above the ground
+    flatten: f32,
+    opacity: f32,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Shadow {
+            offset: 20.0,
+            flatten: 0.1,
+            opacity: 0.4,
+        }
+    }
+}
+
+// Where the shadow of `p` falls on the ground at height `ground_y`. Points below the ground cast
+// their shadow onto it.
+fn shadow_point(shadow: &Shadow, p: Vec3, ground_y: f32) -> Vec3 {
+    let height = (ground_y - p.y).max(0.0);
+
+    Vec3{x: p.x + shadow.offset, y: ground_y - height * shadow.flatten, z: 0.0}
+}
+
+fn collide_floor(floor: &Floor, pos: &mut Vec3, old_pos: &mut Vec3) {
+    if pos.y < floor.y {
+        return;
+    }
+
+    pos.y = floor.y;
+    // Verlet velocity is pos - old_pos, so dragging old_pos along slows the particle down
+    old_pos.x += (pos.x - old_pos.x) * floor.friction;
+    old_pos.z += (pos.z - old_pos.z) * floor.friction;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FoldAxis {
+    Row,
+    Column,
+}
+
+// Crease the cloth starts with: every particle past row or column `index` is moved `depth` along z.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct Fold {
+    axis: FoldAxis,
+    index: usize,
+    depth: f32,
+}
+
+// Relaxation factor for solver iteration `iteration`. Iterations past the end of the schedule
+// keep using its last factor, and an empty schedule means no relaxation.
+fn relaxation_factor(schedule: &[f32], iteration: usize) -> f32 {
+    schedule.get(iteration).or(schedule.last()).copied().unwrap_or(1.0)
+}
+
+// Order of the phases within a step. Constraints are always satisfied last.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum StepOrder {
+    // The default. Integrates with the forces accumulated during the previous step, then
+    // accumulates the forces for the next one. Forces lag one step behind the positions,
+    // so a cloth at rest doesn't move at all on its very first step.
+    IntegrateThenForces,
+    // Accumulates forces from the current positions and integrates with them right away,
+    // like most position based dynamics references.
+    ForcesThenIntegrate,
+}
+
+// Fakes depth in the 2D view: particles further away (larger z) are drawn smaller. At z = 0 the
+// scale is 1 and it halves every `depth` units, limited to [min_scale, max_scale].
+fn perspective_scale(z: f32, depth: f32, min_scale: f32, max_scale: f32) -> f32 {
+    if depth <= 0.0 {
+        return 1.0;
+    }
+
+    clamp(0.5_f32.powf(z / depth), min_scale, max_scale)
+}
+
+// Opacity of a particle `age` seconds after it was added, ramping from 0 to 1 over `duration`.
+fn fade_alpha(age: f32, duration: f32) -> f32 {
+    if duration <= 0.0 {
+        return 1.0;
+    }
+
+    clamp(age / duration, 0.0, 1.0)
+}
+
+fn with_alpha(color: Color, alpha: f32) -> Color {
+    Color::new(color.r, color.g, color.b, color.a * alpha)
+}
+
+// Darkens the color toward black for scales below 1, keeping the alpha.
+fn dim_color(color: Color, scale: f32) -> Color {
+    let factor = scale.min(1.0);
+    Color::new(color.r * factor, color.g * factor, color.b * factor, color.a)
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
 struct Constraint {
     idx_1: usize,
     idx_2: usize,
     rest_length: f32,
+    // The constraint tears when stretched beyond this many times its rest length
+    max_stretch: f32,
+    // Cleared once the constraint has torn
+    active: bool,
+    // Fraction of the length error corrected per iteration, 1 is fully rigid
+    #[serde(default = "default_stiffness")]
+    stiffness: f32,
+    // When set the constraint is solved with XPBD instead, as a spring of this compliance
+    // (inverse stiffness) that behaves the same for any iteration count and time step. 0 is rigid.
+    #[serde(default)]
+    compliance: Option<f32>,
+    // XPBD Lagrange multiplier accumulated over the iterations of a step
+    #[serde(skip)]
+    lambda: f32,
 }
 
-#[derive(Clone, Copy)]
-struct PointConstraint {
-    idx: usize,
+fn default_stiffness() -> f32 {
+    1.0
+}
+
+impl Constraint {
+    // A constraint between a particle and itself has no direction to correct along, these can
+    // come from a malformed state file and are skipped by the solver.
+    fn is_self_constraint(&self) -> bool {
+        self.idx_1 == self.idx_2
+    }
+}
+
+// Pulls every particle within `radius` of `point` toward it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Magnet {
     point: Vec3,
+    radius: f32,
+    strength: f32,
 }
 
-#[macroquad::main("BasicShapes")]
-async fn main() {
-    const NUM_COLS:        usize = 10;
-    const NUM_ROWS:        usize = 10;
-    const NUM_PARTICLES:   usize = NUM_ROWS * NUM_COLS;
-    const NUM_CONSTRAINTS: usize = (NUM_ROWS - 1) * NUM_COLS + (NUM_COLS - 1) * NUM_ROWS;
-    const NUM_ITERATIONS:  usize = 1;
-    const START_DISTANCE:  f32   = 20.0;
-    const PARTICLE_RADIUS: f32   = 3.0;
-    const INTERSECT_THRESHOLD: f32 = PARTICLE_RADIUS + 3.0;
-    
-    let mut pos     = [Vec3{x: 0.0, y: 0.0, z: 0.0}; NUM_PARTICLES];
-    let mut old_pos = [Vec3{x: 0.0, y: 0.0, z: 0.0}; NUM_PARTICLES];
-    let mut forces  = [Vec3{x: 0.0, y: 0.0, z: 0.0}; NUM_PARTICLES];
-    let mut constraints = [Constraint{idx_1: 0, idx_2: 0, rest_length: 0.0}; NUM_CONSTRAINTS];
-    let mut point_constraints: Vec<PointConstraint> = Vec::new();
+fn magnet_force(magnet: &Magnet, p: Vec3) -> Vec3 {
+    let delta = magnet.point - p;
+    if delta.length() > magnet.radius {
+        return Vec3::default();
+    }
 
-    let mut holding_particle = false;
-    // The particle that the mouse is "holding"
-    let mut held_constraint = PointConstraint {
-        idx: 0,
-        point: Vec3 {
-            x: 0.0, y: 0.0, z: 0.0
-        }
-    };
-    
-    let gravity = Vec3{x: 0.0, y: 10.0 * 9.82, z: 0.0};
-    let time_step = 0.01666667;
+    delta.normalized() * magnet.strength
+}
+
+// Gravity eased in linearly from zero over the first `ramp_steps` steps, so a cloth starting
+// flat isn't yanked on the first frame.
+fn ramped_gravity(gravity: Vec3, step: usize, ramp_steps: usize) -> Vec3 {
+    if step >= ramp_steps {
+        return gravity;
+    }
 
-    // Randomized initial conditions
-    /*for p in 0..NUM_PARTICLES {
-        pos[p].x = screen_width() / 2.0  + random_f32(-90.0, 90.0);
-        pos[p].y = screen_height() / 2.0 + random_f32(-90.0, 90.0);
+    gravity * (step as f32 / ramp_steps as f32)
+}
 
-        old_pos[p].x = pos[p].x + random_f32(-1.0, 1.0);
-        old_pos[p].y = pos[p].y + random_f32(-1.0, 1.0);
-    }*/
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum PulseWave {
+    Sine,
+    Square,
+}
 
-    for p in 0..NUM_PARTICLES {
-        pos[p].x = screen_width() / 2.0;
-        pos[p].y = screen_height() / 2.0;
-        
-        let cols = 10;
-        let row = (p / cols) as f32;
-        let col = (p % cols) as f32;
+// Periodic modulation of gravity, starting at full strength and inverted half a period later.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct GravityPulse {
+    wave: PulseWave,
+    period: f32,
+}
+
+impl GravityPulse {
+    // Factor gravity is scaled by at sim time `time`, between -1 and 1.
+    fn factor(&self, time: f32) -> f32 {
+        if self.period <= 0.0 {
+            return 1.0;
+        }
+
+        let phase = (time / self.period).rem_euclid(1.0);
 
-        pos[p].x += col * START_DISTANCE + random_f32(-1.0, 1.0);
-        pos[p].y += row * START_DISTANCE + random_f32(-1.0, 1.0);
+        match self.wave {
+            PulseWave::Sine   => (phase * std::f32::consts::TAU).cos(),
+            PulseWave::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+        }
+    }
+}
 
-        old_pos[p] = pos[p];
+// Eases the wind toward the cursor velocity, `delta` being how far the cursor moved over the
+// last `dt` seconds. `smoothing` is the fraction of the difference closed each frame.
+fn mouse_wind(wind: Vec3, delta: Vec3, dt: f32, scale: f32, smoothing: f32) -> Vec3 {
+    if dt <= 0.0 {
+        return wind;
     }
 
-    let mut c_idx = 0;
-    // Horizontal
-    for p_x in 0..NUM_ROWS {
-        for p_y in 0..NUM_COLS-1 {
-            let p_idx = p_x * NUM_COLS + p_y;
+    let target = delta * (scale / dt);
+    wind + (target - wind) * smoothing
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct WindKeyframe {
+    time: f32,
+    wind: Vec3,
+}
+
+// Wind at `time` seconds, linearly interpolated between the keyframes, which must be sorted by
+// time. Before the first and after the last keyframe their wind is held.
+fn wind_at(profile: &[WindKeyframe], time: f32) -> Vec3 {
+    let (Some(first), Some(last)) = (profile.first(), profile.last()) else {
+        return Vec3::default();
+    };
 
-            constraints[c_idx].idx_1 = p_idx;
-            constraints[c_idx].idx_2 = p_idx + 1;
-            constraints[c_idx].rest_length = 20.0;
+    if time <= first.time {
+        return first.wind;
+    }
 
-            c_idx += 1;
+    for pair in profile.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if time < b.time {
+            let t = (time - a.time) / (b.time - a.time);
+            return a.wind + (b.wind - a.wind) * t;
         }
     }
-    
-    // Vertical
-    for p_y in 0..NUM_COLS {
-        for p_x in 0..NUM_ROWS-1 {
-            let p_idx = p_x * NUM_COLS + p_y;
 
-            constraints[c_idx].idx_1 = p_idx;
-            constraints[c_idx].idx_2 = p_idx + NUM_COLS;
-            constraints[c_idx].rest_length = 20.0;
+    last.wind
+}
+
+// Tip of the debug arrow showing `force` acting on a particle at `p`.
+fn force_arrow_end(p: Vec3, force: Vec3, scale: f32) -> Vec3 {
+    p + force * scale
+}
+
+// Heat map color going from blue at `min` to red at `max`.
+fn stiffness_color(stiffness: f32, min: f32, max: f32) -> Color {
+    let t = if max > min { clamp((stiffness - min) / (max - min), 0.0, 1.0) } else { 1.0 };
+
+    Color::new(t, 0.2, 1.0 - t, 1.0)
+}
+
+// Line width for a constraint stretched to `ratio` times its rest length. Like a rubber band,
+// it gets thinner when stretched and thicker when compressed.
+fn stretch_thickness(base: f32, ratio: f32) -> f32 {
+    if ratio <= 0.0 {
+        return base * 3.0;
+    }
+
+    clamp(base / ratio, base * 0.2, base * 3.0)
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct PointConstraint {
+    idx: usize,
+    point: Vec3,
+    // When set the pin is soft: instead of being held exactly in place, the particle is pulled
+    // toward the point by a spring with this stiffness
+    #[serde(default)]
+    stiffness: Option<f32>,
+    // Damping ratio of a soft pin. Below 1 the particle overshoots and bounces around the
+    // anchor before settling, 0 bounces forever and 1 returns as fast as possible without
+    // overshooting.
+    #[serde(default)]
+    damping: f32,
+}
+
+// Damped spring force of a soft pin on a particle at `p` moving at `velocity`.
+fn soft_pin_force(point: Vec3, p: Vec3, velocity: Vec3, stiffness: f32, damping: f32, mass: f32) -> Vec3 {
+    let damping_coefficient = 2.0 * damping * (stiffness * mass).sqrt();
+    (point - p) * stiffness - velocity * damping_coefficient
+}
+
+// Like a point constraint but with slack, the particle is free to move anywhere within
+// `length` of the anchor point.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct Tether {
+    idx: usize,
+    point: Vec3,
+    length: f32,
+}
+
+// Obstacles are described in a scene file and loaded at startup.
+// Circles and boxes only look at the x/y plane, spheres use all three axes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Obstacle {
+    Circle { center: Vec3, radius: f32 },
+    Sphere { center: Vec3, radius: f32 },
+    Box { min: Vec3, max: Vec3 },
+    // Pulls particles toward the center. The pull falls off with the squared distance.
+    GravityWell { center: Vec3, strength: f32 },
+    // Pushes particles along `direction`, fading out linearly to zero at `radius`.
+    WindSource { position: Vec3, direction: Vec3, strength: f32, radius: f32 },
+}
+
+// Rectangular area (x/y plane) where particles lose an extra fraction of their velocity each step.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct SlowRegion {
+    min: Vec3,
+    max: Vec3,
+    damping: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+struct Scene {
+    obstacles: Vec<Obstacle>,
+    slow_regions: Vec<SlowRegion>,
+}
+
+// Fraction of its velocity a particle at `p` keeps this step.
+fn region_velocity_scale(regions: &[SlowRegion], p: Vec3) -> f32 {
+    let mut scale = 1.0;
 
-            c_idx += 1
+    for region in regions {
+        if p.x >= region.min.x && p.x <= region.max.x && p.y >= region.min.y && p.y <= region.max.y {
+            scale *= 1.0 - clamp(region.damping, 0.0, 1.0);
         }
     }
 
-    point_constraints.push(PointConstraint { idx: 0, point: pos[0]});
-    point_constraints.push(PointConstraint { idx: NUM_COLS / 2, point: pos[NUM_COLS / 2]});
-    point_constraints.push(PointConstraint { idx: NUM_COLS - 1, point: pos[NUM_COLS - 1]});
+    scale
+}
 
-    let mut last_frame = Instant::now();
-    loop {
-        /**** Handle input ****/
-        if is_mouse_button_down(MouseButton::Left) {
-            let mouse = mouse_position();
-            let mouse_vec = Vec3{x: mouse.0, y: mouse.1, z: 0.0};
-
-            if !holding_particle {
-                for p in 0..NUM_PARTICLES {
-                    
-                    if distance(mouse_vec, pos[p]) < INTERSECT_THRESHOLD {
-                        holding_particle = true;
-                        held_constraint.idx = p;
-                        held_constraint.point = mouse_vec;
-                        break;
-                    }
-                }
-            }
-            else {
-                held_constraint.point = mouse_vec;
-            }
+// Colors used by the renderer as RGBA in 0..1
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Palette {
+    particle: [f32; 4],
+    constraint: [f32; 4],
+    pinned: [f32; 4],
+    held: [f32; 4],
+    highlight: [f32; 4],
+    // Marker at the point the held particle is pulled toward
+    held_target: [f32; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            particle: WHITE.into(),
+            constraint: GRAY.into(),
+            pinned: RED.into(),
+            held: YELLOW.into(),
+            highlight: MAGENTA.into(),
+            held_target: ORANGE.into(),
         }
-        else {
-            holding_particle = false;
+    }
+}
+
+impl Palette {
+    // Holding takes precedence over highlighting, which takes precedence over pinning
+    fn particle_color(&self, pinned: bool, held: bool, highlighted: bool) -> Color {
+        if held {
+            Color::from(self.held)
+        } else if highlighted {
+            Color::from(self.highlight)
+        } else if pinned {
+            Color::from(self.pinned)
+        } else {
+            Color::from(self.particle)
         }
+    }
+}
 
+const CONFIG_PATH: &str = "config.toml";
 
-        /**** Update ****/
-        // Verlet integration step
-        for p in 0..NUM_PARTICLES {
-            let tmp = pos[p];
-            pos[p] += pos[p] - old_pos[p] + forces[p] * time_step * time_step;
-            old_pos[p] = tmp;
-        }
+// Settings read from config.toml at startup. The live values can be written back with the C key.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Config {
+    num_iterations: usize,
+    // Factor each solver iteration's corrections are scaled by, in order, e.g. [1.5, 1.2, 1.0]
+    // to over-relax early on. Later iterations keep the last factor.
+    relaxation_schedule: Vec<f32>,
+    gravity: Vec3,
+    // Number of steps over which gravity is eased in from zero at startup, 0 disables the ramp
+    gravity_ramp_steps: usize,
+    // When present, gravity flips or pulses periodically
+    gravity_pulse: Option<GravityPulse>,
+    time_step: f32,
+    // Toggled with D. When set the random number generator is seeded with `seed` and exactly
+    // one time step is simulated per frame, otherwise the seed comes from the clock and the
+    // simulation follows real time.
+    deterministic: bool,
+    seed: u64,
+    boundary_mode: BoundaryMode,
+    step_order: StepOrder,
+    // Use a quadtree to find the particles near obstacles and the cursor instead of checking
+    // every particle. Only pays off for large cloths.
+    use_quadtree: bool,
+    // Pushes apart particles closer than twice the collision radius, both within a cloth and
+    // between different cloths.
+    self_collision: bool,
+    collision_radius: f32,
+    // Number of cloths, laid out side by side
+    num_cloths: usize,
+    // Size of the thread pool the cloths are stepped on, needs the parallel feature.
+    // Defaults to one thread per core.
+    solver_threads: Option<usize>,
+    // Length of the force debug arrows per unit of force
+    force_arrow_scale: f32,
+    // Target time for physics and rendering in seconds. Frames taking longer are counted and
+    // flash a warning. 0 disables the warning.
+    frame_budget: f32,
+    // Sleeps at the end of each frame to stay below this frame rate. 0 or less disables the cap.
+    max_fps: Option<f32>,
+    palette: Palette,
+    // Dot size in the points only render mode
+    point_radius: f32,
+    // Length of the normal debug lines
+    normal_length: f32,
+    // Length of the world axes shown with O
+    axis_length: f32,
+    // Seconds over which particles added with E fade in
+    fade_duration: f32,
+    // Draw the constraints over the particles instead of under them
+    constraints_on_top: bool,
+    // Press P to fake depth by drawing particles smaller the larger their z. The scale halves
+    // every `perspective_depth` units and stays within the min and max.
+    perspective_depth: f32,
+    perspective_min_scale: f32,
+    perspective_max_scale: f32,
+    // Fraction of the length error each constraint corrects per iteration, 1 is rigid
+    stiffness: f32,
+    // When set, constraints are solved with XPBD as springs of this compliance instead of with
+    // the stiffness
+    compliance: Option<f32>,
+    // Stiffness range covered by the heat map render mode
+    heat_map_min_stiffness: f32,
+    heat_map_max_stiffness: f32,
+    // Default tear threshold, as a multiple of the constraint rest length
+    max_stretch: f32,
+    // When a constraint tears, the thresholds of its neighbours are multiplied by this.
+    // 1 disables propagation.
+    tear_propagation: f32,
+    // When set, every torn constraint is logged to this CSV file
+    break_log: Option<String>,
+    // Optional crease the cloth starts with
+    fold: Option<Fold>,
+    // Optional floor that catches torn pieces
+    floor: Option<Floor>,
+    // When present, the particles are kept inside of these bounds instead of the window and the
+    // view follows the cloth
+    world_bounds: Option<WorldBounds>,
+    // When present, a shadow of the cloth is drawn on the floor or the bottom of the window
+    shadow: Option<Shadow>,
+    // When set, a cloth's velocities are scaled down whenever its kinetic energy goes above this
+    max_energy: Option<f32>,
+    // Obstacle penetrations up to the slop are left alone, deeper ones are pushed out the bias
+    // past the surface to reduce jitter
+    collision_slop: f32,
+    collision_bias: f32,
+    // Hold M to pull the particles within the radius toward the cursor
+    magnet_radius: f32,
+    magnet_strength: f32,
+    // Horizontal distance between a cloth and the copy made of it with U
+    clone_offset: f32,
+    // Speed given to every free particle in a random direction when pressing X
+    shake_speed: f32,
+    // Press V to blow wind along the cursor's movement. The wind is the cursor velocity times
+    // the scale, eased in by the smoothing factor each frame.
+    mouse_wind_scale: f32,
+    mouse_wind_smoothing: f32,
+    // Scripted wind over time, interpolated between the keyframes and added to the cursor wind
+    wind_profile: Vec<WindKeyframe>,
+    // Indices of the pinned particles. Defaults to the top corners and middle.
+    pins: Option<Vec<usize>>,
+    // When set the pins are soft springs of this stiffness that give a little under load,
+    // instead of holding their particles exactly in place
+    pin_stiffness: Option<f32>,
+    // Damping ratio of the soft pins. Below 1 they bounce around their anchors before settling.
+    pin_damping: f32,
+    // Indices of particles drawn in the highlight color
+    highlight: Vec<usize>,
+    // Each particle's mass is this times the area of cloth around it. Defaults to
+    // `DEFAULT_DENSITY`, which gives a particle inside the cloth a mass of 1.
+    density: Option<f32>,
+    // Mass of the bottom row of particles, overriding the density
+    hem_mass: Option<f32>,
+    // When set, every bottom row particle is tethered to an anchor this far below it
+    hem_anchor_distance: Option<f32>,
+    hem_tether_length: f32,
+    // Collision radius of the bottom row particles, defaults to `collision_radius`
+    hem_collision_radius: Option<f32>,
+    // Brush size and tear threshold used when painting weak areas
+    paint_radius: f32,
+    paint_max_stretch: f32,
+    // Number of frames at startup during which input is ignored, letting the cloth settle
+    warm_up_frames: usize,
+    // When set, the cloth simulates for this many seconds and then every particle is pinned
+    // in place, leaving a static drape.
+    settle_time: Option<f32>,
+    // Number of steps recorded for time reversal, 0 disables recording
+    history_length: usize,
+    // Pin jiggle stress test, run with --jiggle: each step the pins are moved randomly within
+    // the radius and the test fails if any particle gets further than the limit from the
+    // cloth's starting bounds.
+    jiggle_radius: f32,
+    jiggle_steps: usize,
+    jiggle_limit: f32,
+}
 
-        // Accumulate forces
-        for p in 0..NUM_PARTICLES {
-            forces[p] = gravity;
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            num_iterations: 1,
+            relaxation_schedule: Vec::new(),
+            gravity: Vec3{x: 0.0, y: 10.0 * 9.82, z: 0.0},
+            gravity_ramp_steps: 0,
+            gravity_pulse: None,
+            time_step: 0.01666667,
+            deterministic: false,
+            seed: 0,
+            boundary_mode: BoundaryMode::Clamp,
+            step_order: StepOrder::IntegrateThenForces,
+            use_quadtree: false,
+            self_collision: true,
+            collision_radius: 3.0,
+            num_cloths: 1,
+            solver_threads: None,
+            force_arrow_scale: 0.3,
+            point_radius: 2.0,
+            normal_length: 10.0,
+            axis_length: 100.0,
+            fade_duration: 0.5,
+            constraints_on_top: false,
+            perspective_depth: 200.0,
+            perspective_min_scale: 0.25,
+            perspective_max_scale: 2.0,
+            frame_budget: 0.0166,
+            max_fps: None,
+            palette: Palette::default(),
+            stiffness: 1.0,
+            compliance: None,
+            heat_map_min_stiffness: 0.0,
+            heat_map_max_stiffness: 1.0,
+            max_stretch: 3.0,
+            tear_propagation: 1.0,
+            break_log: None,
+            fold: None,
+            floor: None,
+            world_bounds: None,
+            shadow: None,
+            max_energy: None,
+            collision_slop: 0.0,
+            collision_bias: 0.0,
+            magnet_radius: 100.0,
+            magnet_strength: 400.0,
+            clone_offset: 220.0,
+            shake_speed: 300.0,
+            mouse_wind_scale: 2.0,
+            mouse_wind_smoothing: 0.1,
+            wind_profile: Vec::new(),
+            pins: None,
+            pin_stiffness: None,
+            pin_damping: 0.2,
+            highlight: Vec::new(),
+            density: None,
+            hem_mass: None,
+            hem_anchor_distance: None,
+            hem_tether_length: 40.0,
+            hem_collision_radius: None,
+            paint_radius: 30.0,
+            paint_max_stretch: 1.5,
+            warm_up_frames: 0,
+            settle_time: None,
+            history_length: 600,
+            jiggle_radius: 2.0,
+            jiggle_steps: 2000,
+            jiggle_limit: 400.0,
         }
+    }
+}
 
-        // Satisfy constraints
-        for p in 0..NUM_PARTICLES {
-            pos[p] = vclamp(pos[p], Vec3{x: 0.0, y: 0.0, z: 0.0}, Vec3{x: screen_width(), y: screen_height(), z: 0.0});
+fn parse_config(source: &str) -> Result<Config, toml::de::Error> {
+    toml::from_str(source)
+}
+
+fn load_config(path: &str) -> Result<Config, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    parse_config(&source).map_err(|e| format!("{path}: {e}"))
+}
+
+fn save_config(path: &str, config: &Config) -> Result<(), String> {
+    let source = toml::to_string(config).map_err(|e| format!("{path}: {e}"))?;
+    std::fs::write(path, source).map_err(|e| format!("{path}: {e}"))
+}
+
+fn parse_scene(source: &str) -> Result<Scene, toml::de::Error> {
+    toml::from_str(source)
+}
+
+fn load_scene(path: &str) -> Result<Scene, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    parse_scene(&source).map_err(|e| format!("{path}: {e}"))
+}
+
+// Moves a particle out of a solid obstacle, `bias` past its surface to keep it from sinking
+// straight back in. Penetrations no deeper than `slop` are ignored. Force-only obstacles leave it
+// untouched.
+fn collide_obstacle(obstacle: &Obstacle, p: Vec3, slop: f32, bias: f32) -> Vec3 {
+    match *obstacle {
+        Obstacle::Circle { center, radius } => {
+            let mut delta = p - center;
+            delta.z = 0.0;
+
+            let len = delta.length();
+            if len >= radius - slop || len == 0.0 {
+                return p;
+            }
+
+            p + delta * ((radius - len + bias) / len)
         }
+        Obstacle::Sphere { center, radius } => {
+            let delta = p - center;
 
-        for _i in 0..NUM_ITERATIONS {   
-            for c in 0..NUM_CONSTRAINTS {
-                let p1 = pos[constraints[c].idx_1];
-                let p2 = pos[constraints[c].idx_2];
-                
-                // NOTE: We can approximate this to avoid the sqrt. Unsure how relevant that is on modern systems.
-                let delta = p2 - p1;
-                let delta_len = (delta.x * delta.x + delta.y * delta.y + delta.z + delta.z).sqrt();
-                let diff_len = (delta_len - constraints[c].rest_length) / delta_len;
+            let len = delta.length();
+            if len >= radius - slop || len == 0.0 {
+                return p;
+            }
 
-                pos[constraints[c].idx_1] += delta * 0.5 * diff_len;
-                pos[constraints[c].idx_2] -= delta * 0.5 * diff_len;
+            p + delta * ((radius - len + bias) / len)
+        }
+        Obstacle::Box { min, max } => {
+            if p.x <= min.x || p.x >= max.x || p.y <= min.y || p.y >= max.y {
+                return p;
             }
 
-            for constraint in &point_constraints {
-                pos[constraint.idx] = constraint.point;
+            // Push out through the closest face
+            let mut result = p;
+            let left   = p.x - min.x;
+            let right  = max.x - p.x;
+            let top    = p.y - min.y;
+            let bottom = max.y - p.y;
+            let closest = left.min(right).min(top).min(bottom);
+
+            if closest <= slop {
+                return p;
             }
 
-            if holding_particle {
-                pos[held_constraint.idx] = held_constraint.point;
+            if closest == left {
+                result.x = min.x - bias;
+            } else if closest == right {
+                result.x = max.x + bias;
+            } else if closest == top {
+                result.y = min.y - bias;
+            } else {
+                result.y = max.y + bias;
             }
+
+            result
         }
+        Obstacle::GravityWell { .. } | Obstacle::WindSource { .. } => p,
+    }
+}
 
-        /**** Draw ****/
-        clear_background(BLACK);
-        
-        for c in 0..NUM_CONSTRAINTS {
-            let p1 = pos[constraints[c].idx_1];
-            let p2 = pos[constraints[c].idx_2];
-            draw_line(p1.x, p1.y, p2.x, p2.y, 5.0, GRAY);
+// Axis-aligned x/y bounds of the region an obstacle can push particles out of.
+fn obstacle_bounds(obstacle: &Obstacle) -> Option<(Vec3, Vec3)> {
+    match *obstacle {
+        Obstacle::Circle { center, radius } | Obstacle::Sphere { center, radius } => {
+            let extent = Vec3{x: radius, y: radius, z: 0.0};
+            Some((center - extent, center + extent))
         }
+        Obstacle::Box { min, max } => Some((min, max)),
+        Obstacle::GravityWell { .. } | Obstacle::WindSource { .. } => None,
+    }
+}
+
+// Force-field obstacles contribute to the per-particle force accumulation.
+fn obstacle_force(obstacle: &Obstacle, p: Vec3) -> Vec3 {
+    match *obstacle {
+        Obstacle::GravityWell { center, strength } => {
+            let delta = center - p;
 
-        for p in 0..NUM_PARTICLES {
-            draw_circle(pos[p].x, pos[p].y, PARTICLE_RADIUS, WHITE)
+            // Clamp the distance so particles near the center don't get flung away
+            let dist_sq = (delta.x * delta.x + delta.y * delta.y + delta.z * delta.z).max(100.0);
+            delta.normalized() * (strength / dist_sq)
         }
+        Obstacle::WindSource { position, direction, strength, radius } => {
+            let dist = distance(p, position);
+            if dist >= radius {
+                return Vec3::default();
+            }
 
-        draw_text(last_frame.elapsed().as_secs_f32().to_string().as_str(), 20.0, 20.0, 20.0, DARKGRAY);
+            direction.normalized() * (strength * (1.0 - dist / radius))
+        }
+        _ => Vec3::default(),
+    }
+}
 
-        // finish frame
-        last_frame = Instant::now();
+fn draw_obstacle(obstacle: &Obstacle) {
+    match *obstacle {
+        Obstacle::Circle { center, radius } | Obstacle::Sphere { center, radius } => {
+            draw_circle_lines(center.x, center.y, radius, 2.0, DARKGREEN);
+        }
+        Obstacle::Box { min, max } => {
+            draw_rectangle_lines(min.x, min.y, max.x - min.x, max.y - min.y, 2.0, DARKGREEN);
+        }
+        Obstacle::GravityWell { center, .. } => {
+            draw_circle_lines(center.x, center.y, 6.0, 2.0, PURPLE);
+        }
+        Obstacle::WindSource { position, direction, radius, .. } => {
+            let tip = position + direction.normalized() * radius * 0.25;
+            draw_circle_lines(position.x, position.y, radius, 1.0, DARKBLUE);
+            draw_line(position.x, position.y, tip.x, tip.y, 2.0, DARKBLUE);
+        }
+    }
+}
 
-        next_frame().await
+// Moves every pin anchor by the same amount, keeping their relative layout.
+fn translate_pins(point_constraints: &mut [PointConstraint], delta: Vec3) {
+    for constraint in point_constraints {
+        constraint.point += delta;
     }
-}
\ No newline at end of file
+}
+
+const QUADTREE_NODE_CAPACITY: usize = 8;
+const QUADTREE_MAX_DEPTH:     usize = 8;
+
+// Quadtree over the x/y positions of the particles, rebuilt from scratch whenever it's used.
+struct Quadtree {
+    min: Vec3,
+    max: Vec3,
+    items: Vec<usize>,
+    children: Vec<Quadtree>,
+}
+
+impl Quadtree {
+    fn build(pos: &[Vec3]) -> Quadtree {
+        let mut min = Vec3{x: f32::MAX, y: f32::MAX, z: 0.0};
+        let mut max = Vec3{x: f32::MIN, y: f32::MIN, z: 0.0};
+
+        for p in pos {
+            min.x = min.x.min(p.x);
+            min.y = min.y.min(p.y);
+            max.x = max.x.max(p.x);
+            max.y = max.y.max(p.y);
+        }
+
+        let mut tree = Quadtree { min, max, items: Vec::new(), children: Vec::new() };
+        for p in 0..pos.len() {
+            tree.insert(pos, p, 0);
+        }
+
+        tree
+    }
+
+    fn insert(&mut self, pos: &[Vec3], idx: usize, depth: usize) {
+        if self.children.is_empty() {
+            if self.items.len() < QUADTREE_NODE_CAPACITY || depth >= QUADTREE_MAX_DEPTH {
+                self.items.push(idx);
+                return;
+            }
+
+            self.split(pos, depth);
+        }
+
+        let child = self.child_index(pos[idx]);
+        self.children[child].insert(pos, idx, depth + 1);
+    }
+
+    fn split(&mut self, pos: &[Vec3], depth: usize) {
+        let mid = (self.min + self.max) * 0.5;
+
+        for quadrant in 0..4 {
+            let (min_x, max_x) = if quadrant & 1 == 0 { (self.min.x, mid.x) } else { (mid.x, self.max.x) };
+            let (min_y, max_y) = if quadrant & 2 == 0 { (self.min.y, mid.y) } else { (mid.y, self.max.y) };
+
+            self.children.push(Quadtree {
+                min: Vec3{x: min_x, y: min_y, z: 0.0},
+                max: Vec3{x: max_x, y: max_y, z: 0.0},
+                items: Vec::new(),
+                children: Vec::new(),
+            });
+        }
+
+        for idx in std::mem::take(&mut self.items) {
+            let child = self.child_index(pos[idx]);
+            self.children[child].insert(pos, idx, depth + 1);
+        }
+    }
+
+    fn child_index(&self, p: Vec3) -> usize {
+        let mid = (self.min + self.max) * 0.5;
+
+        let mut quadrant = 0;
+        if p.x >= mid.x {
+            quadrant |= 1;
+        }
+        if p.y >= mid.y {
+            quadrant |= 2;
+        }
+
+        quadrant
+    }
+
+    // Appends every particle inside the rectangle (inclusive) to `out`.
+    fn query(&self, pos: &[Vec3], min: Vec3, max: Vec3, out: &mut Vec<usize>) {
+        if max.x < self.min.x || min.x > self.max.x || max.y < self.min.y || min.y > self.max.y {
+            return;
+        }
+
+        for &idx in &self.items {
+            let p = pos[idx];
+            if p.x >= min.x && p.x <= max.x && p.y >= min.y && p.y <= max.y {
+                out.push(idx);
+            }
+        }
+
+        for child in &self.children {
+            child.query(pos, min, max, out);
+        }
+    }
+}
+
+// Replaces the pins with one per particle at its current position.
+fn pin_all(pos: &[Vec3], point_constraints: &mut Vec<PointConstraint>) {
+    point_constraints.clear();
+    point_constraints.extend(pos.iter().enumerate().map(|(p, &point)| {
+        PointConstraint { idx: p, point, stiffness: None, damping: 0.0 }
+    }));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClothError {
+    PinOutOfRange { index: usize, num_particles: usize },
+    HighlightOutOfRange { index: usize, num_particles: usize },
+    EmptyGrid { num_rows: usize, num_cols: usize },
+    GridMismatch { num_rows: usize, num_cols: usize, num_particles: usize },
+    LengthMismatch { field: &'static str, len: usize, num_particles: usize },
+    ConstraintOutOfRange { constraint: usize, index: usize, num_particles: usize },
+    TetherOutOfRange { index: usize, num_particles: usize },
+    InvalidSpacing { spacing: f32 },
+    InvalidMaxStretch { max_stretch: f32 },
+    InvalidStiffness { stiffness: f32 },
+}
+
+impl std::fmt::Display for ClothError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClothError::PinOutOfRange { index, num_particles } => {
+                write!(f, "pin index {index} is out of range, valid indices are 0..{num_particles}")
+            }
+            ClothError::HighlightOutOfRange { index, num_particles } => {
+                write!(f, "highlight index {index} is out of range, valid indices are 0..{num_particles}")
+            }
+            ClothError::EmptyGrid { num_rows, num_cols } => {
+                write!(f, "a {num_rows}x{num_cols} grid has no particles, it needs at least one row and column")
+            }
+            ClothError::GridMismatch { num_rows, num_cols, num_particles } => {
+                write!(f, "a {num_rows}x{num_cols} grid can't have {num_particles} particles")
+            }
+            ClothError::LengthMismatch { field, len, num_particles } => {
+                write!(f, "{field} has {len} entries but there are {num_particles} particles")
+            }
+            ClothError::ConstraintOutOfRange { constraint, index, num_particles } => {
+                write!(f, "constraint {constraint} refers to particle {index}, valid indices are 0..{num_particles}")
+            }
+            ClothError::TetherOutOfRange { index, num_particles } => {
+                write!(f, "tether index {index} is out of range, valid indices are 0..{num_particles}")
+            }
+            ClothError::InvalidSpacing { spacing } => {
+                write!(f, "spacing {spacing} must be a positive number")
+            }
+            ClothError::InvalidMaxStretch { max_stretch } => {
+                write!(f, "max_stretch {max_stretch} must be greater than 1, or the cloth tears at rest")
+            }
+            ClothError::InvalidStiffness { stiffness } => {
+                write!(f, "stiffness {stiffness} must be between 0 and 1")
+            }
+        }
+    }
+}
+
+// Everything besides the cloth itself that a step depends on.
+#[derive(Debug, Clone, PartialEq)]
+struct SimParams {
+    gravity: Vec3,
+    num_iterations: usize,
+    boundary_mode: BoundaryMode,
+    step_order: StepOrder,
+    bounds_min: Vec3,
+    bounds_max: Vec3,
+    use_quadtree: bool,
+    obstacles: Vec<Obstacle>,
+    slow_regions: Vec<SlowRegion>,
+    // Active while the magnet key is held
+    magnet: Option<Magnet>,
+    // Uniform force on every particle
+    wind: Vec3,
+    tear_propagation: f32,
+    floor: Option<Floor>,
+    relaxation_schedule: Vec<f32>,
+    // Cleared to let every particle move freely, ignoring constraints, tethers and pins
+    solve_constraints: bool,
+    // Cap on the kinetic energy of each cloth
+    max_energy: Option<f32>,
+    collision_slop: f32,
+    collision_bias: f32,
+}
+
+impl Default for SimParams {
+    fn default() -> Self {
+        let config = Config::default();
+
+        SimParams {
+            gravity: config.gravity,
+            num_iterations: config.num_iterations,
+            boundary_mode: config.boundary_mode,
+            step_order: config.step_order,
+            // Macroquad's default window size
+            bounds_min: Vec3{x: 0.0, y: 0.0, z: 0.0},
+            bounds_max: Vec3{x: 800.0, y: 600.0, z: 0.0},
+            use_quadtree: config.use_quadtree,
+            obstacles: Vec::new(),
+            slow_regions: Vec::new(),
+            magnet: None,
+            wind: Vec3::default(),
+            tear_propagation: config.tear_propagation,
+            floor: config.floor,
+            relaxation_schedule: config.relaxation_schedule,
+            solve_constraints: true,
+            max_energy: config.max_energy,
+            collision_slop: config.collision_slop,
+            collision_bias: config.collision_bias,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BreakEvent {
+    // Index into the cloth's constraints
+    constraint: usize,
+    // Length over rest length when it tore
+    stretch: f32,
+}
+
+const BREAK_LOG_HEADER: &str = "time,cloth,constraint,stretch";
+
+// One line of the break log, matching BREAK_LOG_HEADER.
+fn break_log_line(time: f32, cloth: usize, event: &BreakEvent) -> String {
+    format!("{time:.4},{cloth},{},{:.4}", event.constraint, event.stretch)
+}
+
+// Creates the break log, overwriting any previous one, and writes the header.
+fn create_break_log(path: &str) -> Result<std::fs::File, String> {
+    let mut file = std::fs::File::create(path).map_err(|e| format!("{path}: {e}"))?;
+    writeln!(file, "{BREAK_LOG_HEADER}").map_err(|e| format!("{path}: {e}"))?;
+    Ok(file)
+}
+
+// Live data of the particle selected with Ctrl + click.
+#[derive(Debug, Clone, PartialEq)]
+struct ParticleTrace {
+    position: Vec3,
+    velocity: Vec3,
+    // Forces accumulated for the next integration step
+    force: Vec3,
+    // Indices of the intact constraints attached to the particle
+    constraints: Vec<usize>,
+}
+
+// Diagnostics for a single step, measured once it has finished.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct StepSummary {
+    // Largest relative length error of any active constraint, |length - rest| / rest
+    max_residual: f32,
+    kinetic_energy: f32,
+    // Constraints torn during this step
+    broken_constraints: usize,
+    max_velocity: f32,
+    // Furthest any particle moved during the step, |pos - old_pos|
+    max_displacement: f32,
+    // Active constraints whose two ends are the same particle, skipped by the solver
+    self_constraints: usize,
+}
+
+impl StepSummary {
+    // Folds the summary of another cloth stepped in the same frame into this one.
+    fn merge(&mut self, other: &StepSummary) {
+        self.max_residual = self.max_residual.max(other.max_residual);
+        self.kinetic_energy += other.kinetic_energy;
+        self.broken_constraints += other.broken_constraints;
+        self.max_velocity = self.max_velocity.max(other.max_velocity);
+        self.max_displacement = self.max_displacement.max(other.max_displacement);
+        self.self_constraints += other.self_constraints;
+    }
+}
+
+// Serialized as the saved state, without the per-frame parameters and interaction
+#[derive(Clone, Deserialize, Serialize)]
+struct Cloth {
+    num_rows: usize,
+    num_cols: usize,
+    spacing: f32,
+    pos: Vec<Vec3>,
+    old_pos: Vec<Vec3>,
+    forces: Vec<Vec3>,
+    constraints: Vec<Constraint>,
+    point_constraints: Vec<PointConstraint>,
+    tethers: Vec<Tether>,
+    mass: Vec<f32>,
+    // Cleared for particles that have been merged into another one
+    #[serde(default)]
+    active: Vec<bool>,
+    // Collision radius of each particle
+    #[serde(default)]
+    radius: Vec<f32>,
+    // Particles drawn in the highlight color
+    #[serde(skip)]
+    highlight: HashSet<usize>,
+    // Constraints torn during the last step
+    #[serde(skip)]
+    breaks: Vec<BreakEvent>,
+    // Seconds since each particle was added, infinite for the ones the cloth started with
+    #[serde(skip)]
+    age: Vec<f32>,
+    // The particle that the mouse is "holding"
+    #[serde(skip)]
+    held: Option<PointConstraint>,
+    #[serde(skip)]
+    params: SimParams,
+}
+
+impl Cloth {
+    // Grid of particles hanging from its top corners and middle, with the top left particle at `origin`.
+    fn new(num_rows: usize, num_cols: usize, spacing: f32, origin: Vec3, max_stretch: f32) -> Cloth {
+        let num_particles = num_rows * num_cols;
+
+        let mut pos = vec![Vec3{x: 0.0, y: 0.0, z: 0.0}; num_particles];
+
+        // Randomized initial conditions
+        /*for p in 0..num_particles {
+            pos[p].x = origin.x + random_f32(-90.0, 90.0);
+            pos[p].y = origin.y + random_f32(-90.0, 90.0);
+
+            old_pos[p].x = pos[p].x + random_f32(-1.0, 1.0);
+            old_pos[p].y = pos[p].y + random_f32(-1.0, 1.0);
+        }*/
+
+        for (p, particle) in pos.iter_mut().enumerate() {
+            *particle = origin;
+
+            let row = (p / num_cols) as f32;
+            let col = (p % num_cols) as f32;
+
+            particle.x += col * spacing + random_f32(-1.0, 1.0);
+            particle.y += row * spacing + random_f32(-1.0, 1.0);
+        }
+
+        let mut constraints = Vec::new();
+        // Horizontal
+        for p_x in 0..num_rows {
+            for p_y in 0..num_cols-1 {
+                let p_idx = p_x * num_cols + p_y;
+
+                constraints.push(Constraint { idx_1: p_idx, idx_2: p_idx + 1, rest_length: spacing, max_stretch, active: true, stiffness: 1.0, compliance: None, lambda: 0.0 });
+            }
+        }
+
+        // Vertical
+        for p_y in 0..num_cols {
+            for p_x in 0..num_rows-1 {
+                let p_idx = p_x * num_cols + p_y;
+
+                constraints.push(Constraint { idx_1: p_idx, idx_2: p_idx + num_cols, rest_length: spacing, max_stretch, active: true, stiffness: 1.0, compliance: None, lambda: 0.0 });
+            }
+        }
+
+        let point_constraints = vec![
+            PointConstraint { idx: 0, point: pos[0], stiffness: None, damping: 0.0 },
+            PointConstraint { idx: num_cols / 2, point: pos[num_cols / 2], stiffness: None, damping: 0.0 },
+            PointConstraint { idx: num_cols - 1, point: pos[num_cols - 1], stiffness: None, damping: 0.0 },
+        ];
+
+        Cloth {
+            num_rows,
+            num_cols,
+            spacing,
+            old_pos: pos.clone(),
+            forces: vec![Vec3{x: 0.0, y: 0.0, z: 0.0}; num_particles],
+            pos,
+            constraints,
+            point_constraints,
+            tethers: Vec::new(),
+            mass: vec![1.0; num_particles],
+            active: vec![true; num_particles],
+            radius: vec![Config::default().collision_radius; num_particles],
+            highlight: HashSet::new(),
+            breaks: Vec::new(),
+            age: vec![f32::INFINITY; num_particles],
+            held: None,
+            params: SimParams::default(),
+        }
+    }
+
+    // Replaces the pins with ones holding the given particles where they are now.
+    fn set_pins(&mut self, pins: &[usize]) -> Result<(), ClothError> {
+        if let Some(&index) = pins.iter().find(|&&p| p >= self.pos.len()) {
+            return Err(ClothError::PinOutOfRange { index, num_particles: self.pos.len() });
+        }
+
+        self.point_constraints = pins.iter()
+            .map(|&p| PointConstraint { idx: p, point: self.pos[p], stiffness: None, damping: 0.0 })
+            .collect();
+
+        Ok(())
+    }
+
+    // Pins the particle where it is now, or unpins it if it's already pinned. New pins get the
+    // stiffness and damping of the other pins, see `set_pin_stiffness`.
+    fn toggle_pin(&mut self, p: usize, stiffness: Option<f32>, damping: f32) {
+        let pinned = self.point_constraints.len();
+        self.point_constraints.retain(|constraint| constraint.idx != p);
+
+        if self.point_constraints.len() == pinned {
+            self.point_constraints.push(PointConstraint { idx: p, point: self.pos[p], stiffness, damping });
+        }
+    }
+
+    // Checks that every per-particle array and index agrees with the number of particles, as
+    // they can't be trusted after loading a state.
+    fn validate(&self) -> Result<(), ClothError> {
+        let num_particles = self.pos.len();
+
+        if self.num_rows * self.num_cols != num_particles {
+            return Err(ClothError::GridMismatch { num_rows: self.num_rows, num_cols: self.num_cols, num_particles });
+        }
+
+        let lengths = [
+            ("old_pos", self.old_pos.len()),
+            ("forces", self.forces.len()),
+            ("mass", self.mass.len()),
+            ("active", self.active.len()),
+            ("radius", self.radius.len()),
+            ("age", self.age.len()),
+        ];
+        if let Some(&(field, len)) = lengths.iter().find(|&&(_, len)| len != num_particles) {
+            return Err(ClothError::LengthMismatch { field, len, num_particles });
+        }
+
+        for (c, constraint) in self.constraints.iter().enumerate() {
+            if let Some(index) = [constraint.idx_1, constraint.idx_2].into_iter().find(|&p| p >= num_particles) {
+                return Err(ClothError::ConstraintOutOfRange { constraint: c, index, num_particles });
+            }
+        }
+        if let Some(constraint) = self.point_constraints.iter().find(|constraint| constraint.idx >= num_particles) {
+            return Err(ClothError::PinOutOfRange { index: constraint.idx, num_particles });
+        }
+        if let Some(tether) = self.tethers.iter().find(|tether| tether.idx >= num_particles) {
+            return Err(ClothError::TetherOutOfRange { index: tether.idx, num_particles });
+        }
+
+        Ok(())
+    }
+
+    // Draws the given particles in the highlight color, replacing any previous highlight
+    fn set_highlight(&mut self, particles: &[usize]) -> Result<(), ClothError> {
+        if let Some(&index) = particles.iter().find(|&&p| p >= self.pos.len()) {
+            return Err(ClothError::HighlightOutOfRange { index, num_particles: self.pos.len() });
+        }
+
+        self.highlight = particles.iter().copied().collect();
+
+        Ok(())
+    }
+
+    // None makes every pin hard, otherwise they become soft pins with the given spring stiffness
+    // and damping ratio
+    fn set_pin_stiffness(&mut self, stiffness: Option<f32>, damping: f32) {
+        for constraint in &mut self.point_constraints {
+            constraint.stiffness = stiffness;
+            constraint.damping = damping;
+        }
+    }
+
+    fn set_stiffness(&mut self, stiffness: f32) {
+        for constraint in &mut self.constraints {
+            constraint.stiffness = stiffness;
+        }
+    }
+
+    // None solves the constraints with the stiffness, otherwise with XPBD at this compliance
+    fn set_compliance(&mut self, compliance: Option<f32>) {
+        for constraint in &mut self.constraints {
+            constraint.compliance = compliance;
+        }
+    }
+
+    fn set_radius(&mut self, radius: f32) {
+        self.radius.fill(radius);
+    }
+
+    // Moves the particles past the fold line along z, at rest, so the cloth starts creased
+    fn fold(&mut self, fold: &Fold) {
+        for p in 0..self.pos.len() {
+            let line = match fold.axis {
+                FoldAxis::Row    => p / self.num_cols,
+                FoldAxis::Column => p % self.num_cols,
+            };
+
+            if line > fold.index {
+                self.pos[p].z += fold.depth;
+                self.old_pos[p].z += fold.depth;
+            }
+        }
+
+        for constraint in &mut self.point_constraints {
+            constraint.point = self.pos[constraint.idx];
+        }
+    }
+
+    // Grows the cloth by a row of particles at rest one spacing below the bottom row, attached
+    // to it and to each other. The new particles weigh the same as the ones above them.
+    fn add_row(&mut self) {
+        let bottom_row = (self.num_rows - 1) * self.num_cols;
+        let new_row = self.num_rows * self.num_cols;
+        // The new row is made of the same material as the bottom row
+        let source = self.constraints.iter().find(|constraint| constraint.idx_2 >= bottom_row);
+        let max_stretch = source.map_or(Config::default().max_stretch, |constraint| constraint.max_stretch);
+        let stiffness = source.map_or(default_stiffness(), |constraint| constraint.stiffness);
+        let compliance = source.and_then(|constraint| constraint.compliance);
+
+        for col in 0..self.num_cols {
+            let above = bottom_row + col;
+            let p = self.pos[above] + Vec3{x: 0.0, y: self.spacing, z: 0.0};
+
+            self.pos.push(p);
+            self.old_pos.push(p);
+            self.forces.push(Vec3::default());
+            self.mass.push(self.mass[above]);
+            self.active.push(true);
+            self.radius.push(self.radius[above]);
+            self.age.push(0.0);
+
+            self.constraints.push(Constraint { idx_1: above, idx_2: new_row + col, rest_length: self.spacing, max_stretch, active: true, stiffness, compliance, lambda: 0.0 });
+            if col > 0 {
+                self.constraints.push(Constraint { idx_1: new_row + col - 1, idx_2: new_row + col, rest_length: self.spacing, max_stretch, active: true, stiffness, compliance, lambda: 0.0 });
+            }
+        }
+
+        self.num_rows += 1;
+    }
+
+    // Halves the grid resolution by keeping only every other row and column. The kept particles
+    // are rewired to their new neighbours, with each new constraint spanning two old ones and
+    // intact only if both of those were. Pins and tethers on removed particles move to the
+    // nearest kept one.
+    fn decimate(&mut self) {
+        let num_rows = self.num_rows.div_ceil(2);
+        let num_cols = self.num_cols.div_ceil(2);
+        let old_cols = self.num_cols;
+
+        // Old index of the new particle at (row, col), and new index of the kept particle
+        // closest to an old one
+        let kept = |row: usize, col: usize| 2 * row * old_cols + 2 * col;
+        let remap = |p: usize| (p / old_cols / 2) * num_cols + (p % old_cols) / 2;
+
+        let old: HashMap<(usize, usize), Constraint> = self.constraints.iter()
+            .map(|&constraint| ((constraint.idx_1, constraint.idx_2), constraint))
+            .collect();
+
+        // Combines the two old constraints a-b and b-c into one from new particle `idx_1` to `idx_2`
+        let span = |a: usize, b: usize, c: usize, idx_1: usize, idx_2: usize| {
+            match (old.get(&(a, b)), old.get(&(b, c))) {
+                (Some(first), Some(second)) => Constraint {
+                    idx_1,
+                    idx_2,
+                    rest_length: first.rest_length + second.rest_length,
+                    active: first.active && second.active,
+                    ..*first
+                },
+                // One of them was removed, e.g. by merging, so treat it as torn but keep the
+                // material of whichever is left
+                (first, second) => {
+                    let source = first.or(second).or(self.constraints.first());
+                    Constraint {
+                        idx_1,
+                        idx_2,
+                        rest_length: self.spacing * 2.0,
+                        max_stretch: source.map_or(Config::default().max_stretch, |constraint| constraint.max_stretch),
+                        active: false,
+                        stiffness: source.map_or(default_stiffness(), |constraint| constraint.stiffness),
+                        compliance: source.and_then(|constraint| constraint.compliance),
+                        lambda: 0.0,
+                    }
+                }
+            }
+        };
+
+        let mut constraints = Vec::new();
+        // Horizontal
+        for row in 0..num_rows {
+            for col in 0..num_cols - 1 {
+                let a = kept(row, col);
+                constraints.push(span(a, a + 1, a + 2, row * num_cols + col, row * num_cols + col + 1));
+            }
+        }
+
+        // Vertical
+        for col in 0..num_cols {
+            for row in 0..num_rows - 1 {
+                let a = kept(row, col);
+                constraints.push(span(a, a + old_cols, a + 2 * old_cols, row * num_cols + col, (row + 1) * num_cols + col));
+            }
+        }
+
+        let indices: Vec<usize> = (0..num_rows * num_cols).map(|p| kept(p / num_cols, p % num_cols)).collect();
+
+        let mut point_constraints: Vec<PointConstraint> = Vec::new();
+        for constraint in &self.point_constraints {
+            let idx = remap(constraint.idx);
+            if point_constraints.iter().all(|existing| existing.idx != idx) {
+                point_constraints.push(PointConstraint { idx, ..*constraint });
+            }
+        }
+
+        for tether in &mut self.tethers {
+            tether.idx = remap(tether.idx);
+        }
+
+        self.pos = indices.iter().map(|&p| self.pos[p]).collect();
+        self.old_pos = indices.iter().map(|&p| self.old_pos[p]).collect();
+        self.forces = indices.iter().map(|&p| self.forces[p]).collect();
+        self.mass = indices.iter().map(|&p| self.mass[p]).collect();
+        self.active = indices.iter().map(|&p| self.active[p]).collect();
+        self.radius = indices.iter().map(|&p| self.radius[p]).collect();
+        self.age = indices.iter().map(|&p| self.age[p]).collect();
+        self.highlight = self.highlight.iter().map(|&p| remap(p)).collect();
+        self.constraints = constraints;
+        self.point_constraints = point_constraints;
+        self.held = None;
+        self.num_rows = num_rows;
+        self.num_cols = num_cols;
+        self.spacing *= 2.0;
+    }
+
+    // Independent copy of the cloth with everything moved by `offset`, including its pins
+    fn cloned_with_offset(&self, offset: Vec3) -> Cloth {
+        let mut copy = self.clone();
+
+        for p in 0..copy.pos.len() {
+            copy.pos[p] += offset;
+            copy.old_pos[p] += offset;
+        }
+        translate_pins(&mut copy.point_constraints, offset);
+        for tether in &mut copy.tethers {
+            tether.point += offset;
+        }
+        copy.held = None;
+
+        copy
+    }
+
+    // Adds `velocity` to every particle that isn't pinned or held, for steps of `dt` seconds
+    fn shake(&mut self, velocity: Vec3, dt: f32) {
+        let mut fixed = vec![false; self.pos.len()];
+        for constraint in self.point_constraints.iter().chain(&self.held) {
+            fixed[constraint.idx] = true;
+        }
+
+        for (old_pos, fixed) in self.old_pos.iter_mut().zip(fixed) {
+            if !fixed {
+                *old_pos -= velocity * dt;
+            }
+        }
+    }
+
+    // Gives every particle the mass of the cloth around it at the current positions, so where
+    // the cells are larger the particles are heavier.
+    fn set_density(&mut self, density: f32) {
+        let areas = particle_areas(&self.pos, self.num_rows, self.num_cols);
+
+        for (mass, area) in self.mass.iter_mut().zip(areas) {
+            // A particle without any area would have infinite acceleration
+            *mass = (density * area).max(f32::EPSILON);
+        }
+    }
+
+    // Optionally makes the bottom row heavier, gives it a collision radius of `radius`, and
+    // optionally ties each of its particles to an anchor `anchor_distance` below it with a
+    // tether of `tether_length`.
+    fn weight_hem(&mut self, mass: Option<f32>, radius: f32, anchor_distance: Option<f32>, tether_length: f32) {
+        let bottom_row = (self.num_rows - 1) * self.num_cols;
+
+        for p in bottom_row..bottom_row + self.num_cols {
+            if let Some(mass) = mass {
+                self.mass[p] = mass;
+            }
+            self.radius[p] = radius;
+
+            if let Some(anchor_distance) = anchor_distance {
+                let point = self.pos[p] + Vec3{x: 0.0, y: anchor_distance, z: 0.0};
+                self.tethers.push(Tether { idx: p, point, length: tether_length });
+            }
+        }
+    }
+
+    // Advances the cloth by `dt` seconds using `self.params`.
+    fn step(&mut self, dt: f32) -> StepSummary {
+        let params = std::mem::take(&mut self.params);
+        let mut nearby = Vec::new();
+
+        for age in &mut self.age {
+            *age += dt;
+        }
+
+        for constraint in &mut self.constraints {
+            constraint.lambda = 0.0;
+        }
+
+        match params.step_order {
+            StepOrder::IntegrateThenForces => {
+                self.integrate(dt, &params.slow_regions);
+                self.accumulate_forces(&params, dt);
+            }
+            StepOrder::ForcesThenIntegrate => {
+                self.accumulate_forces(&params, dt);
+                self.integrate(dt, &params.slow_regions);
+            }
+        }
+
+        // Satisfy constraints
+        self.apply_boundary(params.boundary_mode, params.bounds_min, params.bounds_max);
+
+        for i in 0..params.num_iterations {
+            if params.solve_constraints {
+                self.satisfy_constraints(relaxation_factor(&params.relaxation_schedule, i), dt);
+                self.satisfy_tethers();
+            }
+            self.collide_obstacles(&params.obstacles, params.use_quadtree, params.collision_slop, params.collision_bias, &mut nearby);
+            if let Some(floor) = &params.floor {
+                self.collide_floor(floor);
+            }
+            if params.solve_constraints {
+                self.apply_pins();
+            }
+        }
+
+        // Free particles would tear everything apart
+        let broken_constraints = if params.solve_constraints {
+            self.tear(params.tear_propagation)
+        } else {
+            self.breaks.clear();
+            0
+        };
+        self.params = params;
+
+        // A blown up particle would spread NaNs to everything it's connected to, so put it back
+        for p in 0..self.pos.len() {
+            if !self.pos[p].is_finite() && self.old_pos[p].is_finite() {
+                self.pos[p] = self.old_pos[p];
+            }
+        }
+
+        if let Some(max_energy) = self.params.max_energy {
+            self.clamp_energy(max_energy, dt);
+        }
+
+        StepSummary {
+            broken_constraints,
+            ..self.measure(dt)
+        }
+    }
+
+    fn measure(&self, dt: f32) -> StepSummary {
+        let mut summary = StepSummary::default();
+
+        for (_, _, rest_length, length) in self.constraints_with_lengths() {
+            let residual = (length - rest_length).abs() / rest_length;
+            summary.max_residual = summary.max_residual.max(residual);
+        }
+
+        summary.self_constraints = self.constraints.iter()
+            .filter(|constraint| constraint.active && constraint.is_self_constraint())
+            .count();
+
+        for p in 0..self.pos.len() {
+            if !self.active[p] {
+                continue;
+            }
+
+            let displacement = (self.pos[p] - self.old_pos[p]).length();
+            let velocity = displacement / dt;
+
+            summary.kinetic_energy += 0.5 * self.mass[p] * velocity * velocity;
+            summary.max_velocity = summary.max_velocity.max(velocity);
+            summary.max_displacement = summary.max_displacement.max(displacement);
+        }
+
+        summary
+    }
+
+    // Scales every velocity down by the same factor when the kinetic energy is above
+    // `max_energy`, so it ends up exactly at the cap
+    fn clamp_energy(&mut self, max_energy: f32, dt: f32) {
+        let energy = self.measure(dt).kinetic_energy;
+        if energy <= max_energy {
+            return;
+        }
+
+        let scale = (max_energy / energy).sqrt();
+        for p in 0..self.pos.len() {
+            self.old_pos[p] = self.pos[p] - (self.pos[p] - self.old_pos[p]) * scale;
+        }
+    }
+
+    // Verlet integration step
+    fn integrate(&mut self, time_step: f32, slow_regions: &[SlowRegion]) {
+        for p in 0..self.pos.len() {
+            if !self.active[p] {
+                continue;
+            }
+
+            let tmp = self.pos[p];
+            let velocity_scale = region_velocity_scale(slow_regions, tmp);
+            let acceleration = self.forces[p] * (1.0 / self.mass[p]);
+            self.pos[p] += (tmp - self.old_pos[p]) * velocity_scale + acceleration * time_step * time_step;
+            self.old_pos[p] = tmp;
+        }
+    }
+
+    // `dt` is the length of the last step, used to find the velocities
+    fn accumulate_forces(&mut self, params: &SimParams, dt: f32) {
+        for p in 0..self.pos.len() {
+            self.forces[p] = params.gravity * self.mass[p] + params.wind;
+
+            for obstacle in &params.obstacles {
+                self.forces[p] += obstacle_force(obstacle, self.pos[p]);
+            }
+
+            if let Some(magnet) = &params.magnet {
+                self.forces[p] += magnet_force(magnet, self.pos[p]);
+            }
+        }
+
+        for constraint in &self.point_constraints {
+            if let Some(stiffness) = constraint.stiffness {
+                let p = constraint.idx;
+                let velocity = (self.pos[p] - self.old_pos[p]) * (1.0 / dt);
+                self.forces[p] += soft_pin_force(constraint.point, self.pos[p], velocity, stiffness, constraint.damping, self.mass[p]);
+            }
+        }
+    }
+
+    fn apply_boundary(&mut self, mode: BoundaryMode, min: Vec3, max: Vec3) {
+        for p in 0..self.pos.len() {
+            apply_boundary(mode, &mut self.pos[p], &mut self.old_pos[p], min, max);
+        }
+    }
+
+    // One relaxation pass over the distance constraints. Each correction is scaled by
+    // `relaxation`, above 1 over-relaxes. Constraints with a compliance aren't relaxed.
+    fn satisfy_constraints(&mut self, relaxation: f32, dt: f32) {
+        let pos = &mut self.pos;
+        let mass = &self.mass;
+
+        for constraint in &mut self.constraints {
+            if !constraint.active || constraint.is_self_constraint() {
+                continue;
+            }
+
+            if let Some(compliance) = constraint.compliance {
+                let inv_mass_1 = 1.0 / mass[constraint.idx_1];
+                let inv_mass_2 = 1.0 / mass[constraint.idx_2];
+
+                let delta = pos[constraint.idx_2] - pos[constraint.idx_1];
+                let len = delta.length();
+                if len == 0.0 {
+                    continue;
+                }
+                let normal = delta * (1.0 / len);
+
+                let alpha = compliance / (dt * dt);
+                let error = len - constraint.rest_length;
+                let delta_lambda = (-error - alpha * constraint.lambda) / (inv_mass_1 + inv_mass_2 + alpha);
+                constraint.lambda += delta_lambda;
+
+                pos[constraint.idx_1] -= normal * (inv_mass_1 * delta_lambda);
+                pos[constraint.idx_2] += normal * (inv_mass_2 * delta_lambda);
+                continue;
+            }
+
+            let p1 = pos[constraint.idx_1];
+            let p2 = pos[constraint.idx_2];
+
+            // NOTE: We can approximate this to avoid the sqrt. Unsure how relevant that is on modern systems.
+            let delta = p2 - p1;
+            let delta_len = (delta.x * delta.x + delta.y * delta.y + delta.z * delta.z).sqrt();
+            let diff_len = (delta_len - constraint.rest_length) / delta_len * constraint.stiffness * relaxation;
+
+            // Heavier particles move less
+            let inv_mass_1 = 1.0 / mass[constraint.idx_1];
+            let inv_mass_2 = 1.0 / mass[constraint.idx_2];
+            let share_1 = inv_mass_1 / (inv_mass_1 + inv_mass_2);
+            let share_2 = inv_mass_2 / (inv_mass_1 + inv_mass_2);
+
+            pos[constraint.idx_1] += delta * share_1 * diff_len;
+            pos[constraint.idx_2] -= delta * share_2 * diff_len;
+        }
+    }
+
+    // Pulls particles that have strayed too far from their tether anchors back within reach.
+    fn satisfy_tethers(&mut self) {
+        for tether in &self.tethers {
+            let delta = self.pos[tether.idx] - tether.point;
+            let len = delta.length();
+
+            if len > tether.length {
+                self.pos[tether.idx] = tether.point + delta * (tether.length / len);
+            }
+        }
+    }
+
+    // Deactivates every constraint stretched past its tear threshold. Returns the number torn.
+    // The constraints sharing a particle with a torn one get their threshold multiplied by
+    // `propagation`, so with a factor below 1 tears tend to keep running. The torn constraints
+    // are kept in `self.breaks` until the next call.
+    fn tear(&mut self, propagation: f32) -> usize {
+        self.breaks.clear();
+
+        for c in 0..self.constraints.len() {
+            let constraint = &mut self.constraints[c];
+            if !constraint.active {
+                continue;
+            }
+
+            let length = distance(self.pos[constraint.idx_1], self.pos[constraint.idx_2]);
+            if length > constraint.rest_length * constraint.max_stretch {
+                constraint.active = false;
+                self.breaks.push(BreakEvent { constraint: c, stretch: length / constraint.rest_length });
+            }
+        }
+
+        if propagation != 1.0 {
+            for c in self.breaks.iter().map(|event| event.constraint) {
+                let (idx_1, idx_2) = (self.constraints[c].idx_1, self.constraints[c].idx_2);
+
+                for neighbour in &mut self.constraints {
+                    let adjacent = neighbour.idx_1 == idx_1 || neighbour.idx_1 == idx_2
+                        || neighbour.idx_2 == idx_1 || neighbour.idx_2 == idx_2;
+
+                    if neighbour.active && adjacent {
+                        // Never below the rest length, or the constraint would tear at rest
+                        neighbour.max_stretch = (neighbour.max_stretch * propagation).max(1.0);
+                    }
+                }
+            }
+        }
+
+        self.breaks.len()
+    }
+
+    // Lowers the tear threshold of every constraint whose midpoint is within `radius` of
+    // `center` to `max_stretch`. Constraints that are already weaker are left alone.
+    fn paint_tear_threshold(&mut self, center: Vec3, radius: f32, max_stretch: f32) {
+        for constraint in &mut self.constraints {
+            let midpoint = (self.pos[constraint.idx_1] + self.pos[constraint.idx_2]) * 0.5;
+
+            if distance(midpoint, center) <= radius {
+                constraint.max_stretch = constraint.max_stretch.min(max_stretch);
+            }
+        }
+    }
+
+    fn collide_floor(&mut self, floor: &Floor) {
+        for p in 0..self.pos.len() {
+            collide_floor(floor, &mut self.pos[p], &mut self.old_pos[p]);
+        }
+    }
+
+    // `nearby` is scratch space for the quadtree query results.
+    fn collide_obstacles(&mut self, obstacles: &[Obstacle], use_quadtree: bool, slop: f32, bias: f32, nearby: &mut Vec<usize>) {
+        if use_quadtree {
+            let tree = Quadtree::build(&self.pos);
+
+            for obstacle in obstacles {
+                if let Some((min, max)) = obstacle_bounds(obstacle) {
+                    nearby.clear();
+                    tree.query(&self.pos, min, max, nearby);
+
+                    for &p in nearby.iter() {
+                        self.pos[p] = collide_obstacle(obstacle, self.pos[p], slop, bias);
+                    }
+                }
+            }
+        } else {
+            for p in 0..self.pos.len() {
+                for obstacle in obstacles {
+                    self.pos[p] = collide_obstacle(obstacle, self.pos[p], slop, bias);
+                }
+            }
+        }
+    }
+
+    // Soft pins act through forces instead, see accumulate_forces
+    fn apply_pins(&mut self) {
+        for constraint in &self.point_constraints {
+            if constraint.stiffness.is_none() {
+                self.pos[constraint.idx] = constraint.point;
+            }
+        }
+
+        if let Some(held) = self.held {
+            self.pos[held.idx] = held.point;
+        }
+    }
+
+    // (idx_1, idx_2, rest_length, current_length) of every active constraint
+    fn constraints_with_lengths(&self) -> impl Iterator<Item = (usize, usize, f32, f32)> + '_ {
+        self.constraints.iter()
+            .filter(|constraint| constraint.active && !constraint.is_self_constraint())
+            .map(|constraint| {
+                let length = distance(self.pos[constraint.idx_1], self.pos[constraint.idx_2]);
+                (constraint.idx_1, constraint.idx_2, constraint.rest_length, length)
+            })
+    }
+
+    // Merges particle `b` into `a`, for closing seams. Everything attached to `b` is moved over
+    // to `a` and `b` is deactivated. Constraints that ended up connecting `a` to itself are torn.
+    fn merge(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+
+        for constraint in &mut self.constraints {
+            if constraint.idx_1 == b {
+                constraint.idx_1 = a;
+            }
+            if constraint.idx_2 == b {
+                constraint.idx_2 = a;
+            }
+
+            if constraint.idx_1 == constraint.idx_2 {
+                constraint.active = false;
+            }
+        }
+
+        for constraint in &mut self.point_constraints {
+            if constraint.idx == b {
+                constraint.idx = a;
+            }
+        }
+
+        for tether in &mut self.tethers {
+            if tether.idx == b {
+                tether.idx = a;
+            }
+        }
+
+        if self.held.is_some_and(|held| held.idx == b) {
+            self.held = None;
+        }
+
+        self.mass[a] += self.mass[b];
+        self.active[b] = false;
+    }
+
+    // Starts holding particle `p` at `point`. Its implicit velocity is killed so the grab takes
+    // over without a jolt, even when catching a fast moving particle.
+    fn grab(&mut self, p: usize, point: Vec3) {
+        self.held = Some(PointConstraint { idx: p, point, stiffness: None, damping: 0.0 });
+        self.old_pos[p] = self.pos[p];
+    }
+
+    // Closest particle within `max_distance` of `point`.
+    fn pick(&self, point: Vec3, max_distance: f32, use_quadtree: bool, nearby: &mut Vec<usize>) -> Option<(usize, f32)> {
+        let nearest = if use_quadtree {
+            let extent = Vec3{x: max_distance, y: max_distance, z: 0.0};
+
+            nearby.clear();
+            Quadtree::build(&self.pos).query(&self.pos, point - extent, point + extent, nearby);
+            nearby.iter()
+                .filter(|&&p| self.active[p])
+                .map(|&p| (p, distance(point, self.pos[p])))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+        } else {
+            nearest_particle(&self.pos, &self.active, point)
+        };
+
+        nearest.filter(|&(_, d)| d < max_distance)
+    }
+
+    // The two triangles of every grid cell whose edges are still intact, wound the same way.
+    fn triangles(&self) -> Vec<[usize; 3]> {
+        let mut edges = HashSet::new();
+        for constraint in &self.constraints {
+            if constraint.active {
+                edges.insert((constraint.idx_1.min(constraint.idx_2), constraint.idx_1.max(constraint.idx_2)));
+            }
+        }
+
+        let mut triangles = Vec::new();
+        let cols = self.num_cols;
+
+        for row in 0..self.num_rows - 1 {
+            for col in 0..cols - 1 {
+                let top_left     = row * cols + col;
+                let top_right    = top_left + 1;
+                let bottom_left  = top_left + cols;
+                let bottom_right = bottom_left + 1;
+
+                if edges.contains(&(top_left, top_right)) && edges.contains(&(top_left, bottom_left)) {
+                    triangles.push([top_left, top_right, bottom_left]);
+                }
+                if edges.contains(&(top_right, bottom_right)) && edges.contains(&(bottom_left, bottom_right)) {
+                    triangles.push([top_right, bottom_right, bottom_left]);
+                }
+            }
+        }
+
+        triangles
+    }
+
+    fn area(&self) -> f32 {
+        cloth_area(&self.pos, self.num_rows, self.num_cols)
+    }
+
+    // Data for the trace panel, with velocities over a step of `dt`
+    fn trace(&self, p: usize, dt: f32) -> ParticleTrace {
+        let constraints = (0..self.constraints.len())
+            .filter(|&c| {
+                let constraint = &self.constraints[c];
+                constraint.active && (constraint.idx_1 == p || constraint.idx_2 == p)
+            })
+            .collect();
+
+        ParticleTrace {
+            position: self.pos[p],
+            velocity: (self.pos[p] - self.old_pos[p]) * (1.0 / dt),
+            force: self.forces[p],
+            constraints,
+        }
+    }
+
+    // How far the lowest particle hangs below the average height of the pins, None without pins
+    fn sag(&self) -> Option<f32> {
+        if self.point_constraints.is_empty() {
+            return None;
+        }
+
+        let pin_y = self.point_constraints.iter()
+            .map(|constraint| constraint.point.y)
+            .sum::<f32>() / self.point_constraints.len() as f32;
+
+        let lowest = (0..self.pos.len())
+            .filter(|&p| self.active[p])
+            .map(|p| self.pos[p].y)
+            .fold(pin_y, f32::max);
+
+        Some(lowest - pin_y)
+    }
+
+    fn rest_area(&self) -> f32 {
+        ((self.num_rows - 1) * (self.num_cols - 1)) as f32 * self.spacing * self.spacing
+    }
+}
+
+// Validated construction of a cloth, e.g.
+// `ClothBuilder::new().rows(20).cols(20).spacing(15.0).pin_corners().build()`.
+#[derive(Debug, Clone)]
+struct ClothBuilder {
+    num_rows: usize,
+    num_cols: usize,
+    spacing: f32,
+    origin: Vec3,
+    max_stretch: f32,
+    stiffness: f32,
+    // None keeps the pins of `Cloth::new`
+    pins: Option<Vec<usize>>,
+    // Overrides `pins` with the top corners of the final grid size
+    pin_corners: bool,
+}
+
+impl ClothBuilder {
+    fn new() -> ClothBuilder {
+        let config = Config::default();
+
+        ClothBuilder {
+            num_rows: NUM_ROWS,
+            num_cols: NUM_COLS,
+            spacing: START_DISTANCE,
+            origin: Vec3::default(),
+            max_stretch: config.max_stretch,
+            stiffness: config.stiffness,
+            pins: None,
+            pin_corners: false,
+        }
+    }
+
+    fn rows(mut self, num_rows: usize) -> ClothBuilder {
+        self.num_rows = num_rows;
+        self
+    }
+
+    fn cols(mut self, num_cols: usize) -> ClothBuilder {
+        self.num_cols = num_cols;
+        self
+    }
+
+    fn spacing(mut self, spacing: f32) -> ClothBuilder {
+        self.spacing = spacing;
+        self
+    }
+
+    // Position of the top left particle
+    fn origin(mut self, origin: Vec3) -> ClothBuilder {
+        self.origin = origin;
+        self
+    }
+
+    fn max_stretch(mut self, max_stretch: f32) -> ClothBuilder {
+        self.max_stretch = max_stretch;
+        self
+    }
+
+    fn stiffness(mut self, stiffness: f32) -> ClothBuilder {
+        self.stiffness = stiffness;
+        self
+    }
+
+    fn pins(mut self, pins: &[usize]) -> ClothBuilder {
+        self.pins = Some(pins.to_vec());
+        self.pin_corners = false;
+        self
+    }
+
+    // Pins only the top left and top right particles, whatever the number of columns ends up being
+    fn pin_corners(mut self) -> ClothBuilder {
+        self.pins = None;
+        self.pin_corners = true;
+        self
+    }
+
+    fn build(&self) -> Result<Cloth, ClothError> {
+        if self.num_rows == 0 || self.num_cols == 0 {
+            return Err(ClothError::EmptyGrid { num_rows: self.num_rows, num_cols: self.num_cols });
+        }
+        if !(self.spacing > 0.0 && self.spacing.is_finite()) {
+            return Err(ClothError::InvalidSpacing { spacing: self.spacing });
+        }
+        if self.max_stretch.is_nan() || self.max_stretch <= 1.0 {
+            return Err(ClothError::InvalidMaxStretch { max_stretch: self.max_stretch });
+        }
+        if !(0.0..=1.0).contains(&self.stiffness) {
+            return Err(ClothError::InvalidStiffness { stiffness: self.stiffness });
+        }
+
+        let mut cloth = Cloth::new(self.num_rows, self.num_cols, self.spacing, self.origin, self.max_stretch);
+        cloth.set_stiffness(self.stiffness);
+        if self.pin_corners {
+            cloth.set_pins(&[0, self.num_cols - 1])?;
+        } else if let Some(pins) = &self.pins {
+            cloth.set_pins(pins)?;
+        }
+
+        Ok(cloth)
+    }
+}
+
+// Buckets the particles of every cloth into a uniform grid over the x/y plane, so that
+// particles close to each other can be found without checking every pair.
+struct SpatialHash {
+    cell_size: f32,
+    // (cloth index, particle index) for every particle in a cell
+    cells: HashMap<(i32, i32), Vec<(usize, usize)>>,
+}
+
+impl SpatialHash {
+    fn new(cell_size: f32) -> SpatialHash {
+        SpatialHash { cell_size, cells: HashMap::new() }
+    }
+
+    fn cell(&self, p: Vec3) -> (i32, i32) {
+        ((p.x / self.cell_size).floor() as i32, (p.y / self.cell_size).floor() as i32)
+    }
+
+    fn rebuild(&mut self, cloths: &[Cloth]) {
+        for particles in self.cells.values_mut() {
+            particles.clear();
+        }
+
+        for (c, cloth) in cloths.iter().enumerate() {
+            for (p, &pos) in cloth.pos.iter().enumerate() {
+                if !cloth.active[p] {
+                    continue;
+                }
+
+                let cell = self.cell(pos);
+                self.cells.entry(cell).or_default().push((c, p));
+            }
+        }
+
+        // Drop the cells the particles have left, or the map keeps growing as the cloth moves
+        self.cells.retain(|_, particles| !particles.is_empty());
+    }
+}
+
+// Steps the cloths, in parallel on a thread pool when built with the parallel feature.
+struct Solver {
+    #[cfg(feature = "parallel")]
+    pool: rayon::ThreadPool,
+}
+
+impl Solver {
+    // `threads` is the size of the thread pool, None lets rayon pick one per core
+    #[cfg(feature = "parallel")]
+    fn new(threads: Option<usize>) -> Result<Solver, String> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(Solver { pool })
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn new(threads: Option<usize>) -> Result<Solver, String> {
+        if threads.is_some() {
+            return Err("solver_threads needs the parallel feature, e.g. cargo run --features parallel".to_string());
+        }
+
+        Ok(Solver {})
+    }
+
+    #[cfg(feature = "parallel")]
+    fn num_threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn num_threads(&self) -> usize {
+        1
+    }
+
+    // Steps every cloth by `dt` with `params`, folding their summaries together
+    #[cfg(feature = "parallel")]
+    fn step(&self, cloths: &mut [Cloth], params: &SimParams, dt: f32) -> StepSummary {
+        use rayon::prelude::*;
+
+        let summaries: Vec<StepSummary> = self.pool.install(|| {
+            cloths.par_iter_mut()
+                .map(|cloth| {
+                    cloth.params = params.clone();
+                    cloth.step(dt)
+                })
+                .collect()
+        });
+
+        let mut summary = StepSummary::default();
+        for other in &summaries {
+            summary.merge(other);
+        }
+        summary
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn step(&self, cloths: &mut [Cloth], params: &SimParams, dt: f32) -> StepSummary {
+        let mut summary = StepSummary::default();
+        for cloth in cloths {
+            cloth.params = params.clone();
+
+            summary.merge(&cloth.step(dt));
+        }
+        summary
+    }
+}
+
+// Pushes apart any two particles closer than the sum of their radii, whether they belong to the
+// same cloth or not. Each particle moves half the penetration depth.
+fn collide_particles(cloths: &mut [Cloth], hash: &mut SpatialHash) {
+    let max_radius = cloths.iter()
+        .flat_map(|cloth| cloth.radius.iter().copied())
+        .fold(0.0, f32::max);
+
+    // Cells must be at least as large as the collision distance for the 3x3 neighbourhood to be enough
+    hash.cell_size = hash.cell_size.max(max_radius * 2.0);
+    hash.rebuild(cloths);
+
+    for (&(cell_x, cell_y), particles) in &hash.cells {
+        for offset_x in -1..=1 {
+            for offset_y in -1..=1 {
+                let Some(others) = hash.cells.get(&(cell_x + offset_x, cell_y + offset_y)) else {
+                    continue;
+                };
+
+                for &a in particles {
+                    for &b in others {
+                        // Every pair shows up twice, once from each side
+                        if a >= b {
+                            continue;
+                        }
+
+                        let min_dist = cloths[a.0].radius[a.1] + cloths[b.0].radius[b.1];
+                        let delta = cloths[b.0].pos[b.1] - cloths[a.0].pos[a.1];
+                        let len = delta.length();
+                        if len >= min_dist || len == 0.0 {
+                            continue;
+                        }
+
+                        let correction = delta * (0.5 * (min_dist - len) / len);
+                        cloths[a.0].pos[a.1] -= correction;
+                        cloths[b.0].pos[b.1] += correction;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Runs the self-collision pass when it's enabled in `config`. When it's off not even the spatial
+// hash is built, as that's most of the cost.
+fn self_collide(cloths: &mut [Cloth], hash: &mut SpatialHash, config: &Config, free_particles: bool) {
+    if !config.self_collision {
+        return;
+    }
+
+    collide_particles(cloths, hash);
+
+    // Collisions must not move pinned or held particles
+    if !free_particles {
+        for cloth in cloths {
+            cloth.apply_pins();
+        }
+    }
+}
+
+const STATE_PATH: &str = "state.json";
+
+// The most recent states of the cloths, one per step, for playing the simulation backward.
+struct History {
+    states: VecDeque<Vec<Cloth>>,
+    capacity: usize,
+}
+
+impl History {
+    fn new(capacity: usize) -> History {
+        History {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Drops the oldest state once the history is full
+    fn record(&mut self, cloths: &[Cloth]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back(cloths.to_vec());
+    }
+
+    // Takes the most recent state, None once the history has been played back to the start
+    fn rewind(&mut self) -> Option<Vec<Cloth>> {
+        self.states.pop_back()
+    }
+
+    fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    // Plays the history backward at the cadence of one state per `time_step` it was recorded
+    // at, consuming the whole steps in `accumulator`. Returns false once it has run out.
+    fn play_backward(&mut self, cloths: &mut Vec<Cloth>, accumulator: &mut f32, time_step: f32) -> bool {
+        while *accumulator >= time_step {
+            *accumulator -= time_step;
+
+            let Some(states) = self.rewind() else {
+                return false;
+            };
+            *cloths = states;
+            for cloth in cloths.iter_mut() {
+                cloth.held = None;
+            }
+        }
+
+        true
+    }
+}
+
+fn save_state(path: &str, cloths: &[Cloth]) -> Result<(), String> {
+    let source = serde_json::to_string_pretty(cloths).map_err(|e| format!("{path}: {e}"))?;
+    std::fs::write(path, source).map_err(|e| format!("{path}: {e}"))
+}
+
+fn parse_state(source: &str) -> Result<Vec<Cloth>, String> {
+    let mut cloths: Vec<Cloth> = serde_json::from_str(source).map_err(|e| e.to_string())?;
+
+    // Older states have no active flags or radii, and the ages aren't saved
+    for (c, cloth) in cloths.iter_mut().enumerate() {
+        cloth.age = vec![f32::INFINITY; cloth.pos.len()];
+
+        if cloth.active.len() != cloth.pos.len() {
+            cloth.active = vec![true; cloth.pos.len()];
+        }
+        if cloth.radius.len() != cloth.pos.len() {
+            cloth.radius = vec![Config::default().collision_radius; cloth.pos.len()];
+        }
+
+        cloth.validate().map_err(|e| format!("cloth {c}: {e}"))?;
+    }
+
+    Ok(cloths)
+}
+
+fn load_state(path: &str) -> Result<Vec<Cloth>, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let cloths = parse_state(&source).map_err(|e| format!("{path}: {e}"))?;
+
+    for cloth in &cloths {
+        let self_constraints = cloth.constraints.iter().filter(|constraint| constraint.is_self_constraint()).count();
+        if self_constraints > 0 {
+            eprintln!("{path}: {self_constraints} constraints connect a particle to itself and will be ignored");
+        }
+    }
+
+    Ok(cloths)
+}
+
+// The thumbnail is saved next to the state with the same name, e.g. state.json -> state.png
+fn thumbnail_path(state_path: &str) -> String {
+    std::path::Path::new(state_path).with_extension("png").to_string_lossy().into_owned()
+}
+
+const THUMBNAIL_WIDTH:  u32 = 160;
+const THUMBNAIL_HEIGHT: u32 = 120;
+
+// Draws the cloths into a small image, zoomed to fit their bounding box.
+fn render_thumbnail(cloths: &[Cloth], settings: &RenderSettings) -> ImageSink {
+    let mut min = Vec3{x: f32::MAX, y: f32::MAX, z: 0.0};
+    let mut max = Vec3{x: f32::MIN, y: f32::MIN, z: 0.0};
+    for cloth in cloths {
+        for p in &cloth.pos {
+            min.x = min.x.min(p.x);
+            min.y = min.y.min(p.y);
+            max.x = max.x.max(p.x);
+            max.y = max.y.max(p.y);
+        }
+    }
+
+    let mut sink = ImageSink::new(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT, BLACK);
+    sink.fit(min, max);
+
+    for cloth in cloths {
+        draw_cloth(cloth, settings, &mut sink);
+    }
+
+    sink
+}
+
+const RECORDING_PATH: &str = "recording.gltf";
+// Every frame is a morph target holding every particle, so recordings are capped at a minute of
+// steps at the default time step
+#[cfg(feature = "gltf")]
+const MAX_RECORDING_FRAMES: usize = 3600;
+
+#[cfg(feature = "gltf")]
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+// Per-step positions of every cloth, exported as a glTF mesh with one morph target per frame
+// and a step animation of the morph weights that shows one frame at a time.
+#[cfg(feature = "gltf")]
+struct GltfRecording {
+    // Triangles of all cloths over the concatenated particles, taken when recording started
+    indices: Vec<u32>,
+    // Every frame must have the particle count the recording started with
+    num_particles: usize,
+    frames: Vec<Vec<Vec3>>,
+    time_step: f32,
+}
+
+#[cfg(feature = "gltf")]
+impl GltfRecording {
+    fn new(cloths: &[Cloth], time_step: f32) -> GltfRecording {
+        let mut indices = Vec::new();
+        let mut offset = 0;
+        for cloth in cloths {
+            for triangle in cloth.triangles() {
+                indices.extend(triangle.iter().map(|&p| (offset + p) as u32));
+            }
+            offset += cloth.pos.len();
+        }
+
+        GltfRecording {
+            indices,
+            num_particles: offset,
+            frames: Vec::new(),
+            time_step,
+        }
+    }
+
+    // Fails without recording the frame when particles were added or removed since the
+    // recording started, the morph targets can't represent that, or once the recording is full.
+    fn record(&mut self, cloths: &[Cloth]) -> Result<(), String> {
+        if self.frames.len() >= MAX_RECORDING_FRAMES {
+            return Err(format!("the recording is full at {MAX_RECORDING_FRAMES} frames"));
+        }
+
+        let num_particles: usize = cloths.iter().map(|cloth| cloth.pos.len()).sum();
+        if num_particles != self.num_particles {
+            return Err(format!(
+                "the recording started with {} particles but there are now {num_particles}",
+                self.num_particles,
+            ));
+        }
+
+        // glTF is y up while the screen is y down
+        let frame = cloths.iter()
+            .flat_map(|cloth| cloth.pos.iter())
+            .map(|p| Vec3{x: p.x, y: -p.y, z: p.z})
+            .collect();
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    fn to_json(&self) -> Result<serde_json::Value, String> {
+        let Some(base) = self.frames.first() else {
+            return Err("nothing was recorded".to_string());
+        };
+        let num_frames = self.frames.len();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut buffer_views = Vec::new();
+        let mut accessors = Vec::new();
+
+        // Appends the bytes as a new buffer view with a single accessor into it
+        let mut push = |bytes: Vec<u8>, target: Option<u32>, mut accessor: serde_json::Value| {
+            let mut view = serde_json::json!({
+                "buffer": 0,
+                "byteOffset": buffer.len(),
+                "byteLength": bytes.len(),
+            });
+            if let Some(target) = target {
+                view["target"] = target.into();
+            }
+            buffer.extend(bytes);
+
+            accessor["bufferView"] = buffer_views.len().into();
+            buffer_views.push(view);
+            accessors.push(accessor);
+            accessors.len() - 1
+        };
+
+        let vec3_accessor = |points: &[Vec3]| -> (Vec<u8>, serde_json::Value) {
+            let mut min = [f32::MAX; 3];
+            let mut max = [f32::MIN; 3];
+            let mut bytes = Vec::with_capacity(points.len() * 12);
+            for p in points {
+                for (axis, value) in [p.x, p.y, p.z].into_iter().enumerate() {
+                    min[axis] = min[axis].min(value);
+                    max[axis] = max[axis].max(value);
+                    bytes.extend(value.to_le_bytes());
+                }
+            }
+
+            let accessor = serde_json::json!({
+                "componentType": 5126,
+                "count": points.len(),
+                "type": "VEC3",
+                "min": min,
+                "max": max,
+            });
+            (bytes, accessor)
+        };
+
+        let indices = push(
+            self.indices.iter().flat_map(|i| i.to_le_bytes()).collect(),
+            Some(34963),
+            serde_json::json!({"componentType": 5125, "count": self.indices.len(), "type": "SCALAR"}),
+        );
+
+        let (bytes, accessor) = vec3_accessor(base);
+        let positions = push(bytes, Some(34962), accessor);
+
+        let mut targets = Vec::new();
+        for frame in &self.frames {
+            let deltas: Vec<Vec3> = frame.iter().zip(base).map(|(&p, &b)| p - b).collect();
+            let (bytes, accessor) = vec3_accessor(&deltas);
+            targets.push(serde_json::json!({"POSITION": push(bytes, Some(34962), accessor)}));
+        }
+
+        let times: Vec<f32> = (0..num_frames).map(|i| i as f32 * self.time_step).collect();
+        let input = push(
+            times.iter().flat_map(|t| t.to_le_bytes()).collect(),
+            None,
+            serde_json::json!({
+                "componentType": 5126,
+                "count": num_frames,
+                "type": "SCALAR",
+                "min": [times[0]],
+                "max": [times[num_frames - 1]],
+            }),
+        );
+
+        // At keyframe i only morph target i is weighted in. Stored densely that's num_frames
+        // squared weights, so only the ones are stored in a sparse accessor, at index i * (n + 1)
+        // of the weights, and every other weight defaults to 0.
+        let mut sparse: Vec<u8> = (0..num_frames).flat_map(|i| ((i * (num_frames + 1)) as u32).to_le_bytes()).collect();
+        sparse.extend((0..num_frames).flat_map(|_| 1.0f32.to_le_bytes()));
+        let output = push(
+            sparse,
+            None,
+            serde_json::json!({"componentType": 5126, "count": num_frames * num_frames, "type": "SCALAR"}),
+        );
+        // The view holds the sparse indices followed by the values, not the weights themselves
+        let view = accessors[output].as_object_mut().and_then(|accessor| accessor.remove("bufferView"));
+        accessors[output]["sparse"] = serde_json::json!({
+            "count": num_frames,
+            "indices": {"bufferView": view, "componentType": 5125},
+            "values": {"bufferView": view, "byteOffset": num_frames * 4},
+        });
+
+        let mut initial_weights = vec![0.0; num_frames];
+        initial_weights[0] = 1.0;
+
+        Ok(serde_json::json!({
+            "asset": {"version": "2.0", "generator": "cloth_sim"},
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0}],
+            "meshes": [{
+                "primitives": [{
+                    "attributes": {"POSITION": positions},
+                    "indices": indices,
+                    "targets": targets,
+                }],
+                "weights": initial_weights,
+            }],
+            "animations": [{
+                "samplers": [{"input": input, "output": output, "interpolation": "STEP"}],
+                "channels": [{"sampler": 0, "target": {"node": 0, "path": "weights"}}],
+            }],
+            "buffers": [{
+                "byteLength": buffer.len(),
+                "uri": format!("data:application/octet-stream;base64,{}", base64(&buffer)),
+            }],
+            "bufferViews": buffer_views,
+            "accessors": accessors,
+        }))
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        let json = self.to_json().map_err(|e| format!("{path}: {e}"))?;
+        std::fs::write(path, json.to_string()).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+const KEYMAP_PATH: &str = "keys.toml";
+
+// Keys that can be used in keys.toml, referred to by their `KeyCode` name
+const BINDABLE_KEYS: &[KeyCode] = &[
+    KeyCode::Space, KeyCode::Enter, KeyCode::Tab, KeyCode::Backspace, KeyCode::Escape,
+    KeyCode::Insert, KeyCode::Delete, KeyCode::Home, KeyCode::End, KeyCode::PageUp, KeyCode::PageDown,
+    KeyCode::Left, KeyCode::Right, KeyCode::Up, KeyCode::Down,
+    KeyCode::Apostrophe, KeyCode::Comma, KeyCode::Minus, KeyCode::Period, KeyCode::Slash,
+    KeyCode::Semicolon, KeyCode::Equal, KeyCode::LeftBracket, KeyCode::Backslash,
+    KeyCode::RightBracket, KeyCode::GraveAccent,
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+    KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+    KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+    KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+    KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10, KeyCode::F11, KeyCode::F12,
+];
+
+fn parse_key(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS.iter()
+        .copied()
+        .find(|key| format!("{key:?}").eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Action {
+    Pause,
+    Help,
+    RenderMode,
+    StretchLines,
+    Forces,
+    Normals,
+    Area,
+    Stats,
+    Paint,
+    Magnet,
+    Merge,
+    MouseWind,
+    Perspective,
+    Reverse,
+    Sag,
+    Record,
+    Deterministic,
+    Shake,
+    Axes,
+    CloneCloth,
+    FreeParticles,
+    Decimate,
+    AddRow,
+    PinNearest,
+    SelfCollision,
+    Boundary,
+    SaveConfig,
+    SaveState,
+    LoadState,
+}
+
+impl Action {
+    const ALL: [Action; 29] = [
+        Action::Pause,
+        Action::Help,
+        Action::RenderMode,
+        Action::StretchLines,
+        Action::Forces,
+        Action::Normals,
+        Action::Area,
+        Action::Stats,
+        Action::Paint,
+        Action::Magnet,
+        Action::Merge,
+        Action::MouseWind,
+        Action::Perspective,
+        Action::Reverse,
+        Action::Sag,
+        Action::Record,
+        Action::Deterministic,
+        Action::Shake,
+        Action::Axes,
+        Action::CloneCloth,
+        Action::FreeParticles,
+        Action::Decimate,
+        Action::AddRow,
+        Action::PinNearest,
+        Action::SelfCollision,
+        Action::Boundary,
+        Action::SaveConfig,
+        Action::SaveState,
+        Action::LoadState,
+    ];
+
+    // Name used in keys.toml
+    fn name(self) -> &'static str {
+        match self {
+            Action::Pause         => "pause",
+            Action::Help          => "help",
+            Action::RenderMode    => "render_mode",
+            Action::StretchLines  => "stretch_lines",
+            Action::Forces        => "forces",
+            Action::Normals       => "normals",
+            Action::Area          => "area",
+            Action::Stats         => "stats",
+            Action::Paint         => "paint",
+            Action::Magnet        => "magnet",
+            Action::Merge         => "merge",
+            Action::MouseWind     => "mouse_wind",
+            Action::Perspective   => "perspective",
+            Action::Reverse       => "reverse",
+            Action::Sag           => "sag",
+            Action::Record        => "record",
+            Action::Deterministic => "deterministic",
+            Action::Shake         => "shake",
+            Action::Axes          => "axes",
+            Action::CloneCloth    => "clone_cloth",
+            Action::FreeParticles => "free_particles",
+            Action::Decimate      => "decimate",
+            Action::AddRow        => "add_row",
+            Action::PinNearest    => "pin_nearest",
+            Action::SelfCollision => "self_collision",
+            Action::Boundary      => "boundary",
+            Action::SaveConfig    => "save_config",
+            Action::SaveState     => "save_state",
+            Action::LoadState     => "load_state",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Action::Pause         => "pause the simulation",
+            Action::Help          => "show this help",
+            Action::RenderMode    => "cycle render mode",
+            Action::StretchLines  => "scale constraint width with stretch",
+            Action::Forces        => "show particle forces",
+            Action::Normals       => "show triangle normals",
+            Action::Area          => "show surface area",
+            Action::Stats         => "show step statistics",
+            Action::Paint         => "paint weak spots",
+            Action::Magnet        => "magnet (hold)",
+            Action::Merge         => "merge held particle into the nearest one",
+            Action::MouseWind     => "toggle wind from cursor movement",
+            Action::Perspective   => "toggle fake depth perspective",
+            Action::Reverse       => "toggle playing time backward",
+            Action::Sag           => "show sag below the pins",
+            Action::Record        => "start / stop recording to recording.gltf",
+            Action::Deterministic => "toggle deterministic mode",
+            Action::Shake         => "shake the cloth in a random direction",
+            Action::Axes          => "show the world axes",
+            Action::CloneCloth    => "copy the last cloth next to it",
+            Action::FreeParticles => "toggle ignoring constraints and pins",
+            Action::Decimate      => "halve the grid resolution",
+            Action::AddRow        => "add a row to the bottom",
+            Action::PinNearest    => "pin or unpin the nearest particle",
+            Action::SelfCollision => "toggle self-collision",
+            Action::Boundary      => "toggle clamp/wrap boundary",
+            Action::SaveConfig    => "save settings to config.toml",
+            Action::SaveState     => "save cloth state to state.json",
+            Action::LoadState     => "load cloth state from state.json",
+        }
+    }
+
+    fn default_key(self) -> KeyCode {
+        match self {
+            Action::Pause         => KeyCode::Space,
+            Action::Help          => KeyCode::H,
+            Action::RenderMode    => KeyCode::R,
+            Action::StretchLines  => KeyCode::T,
+            Action::Forces        => KeyCode::F,
+            Action::Normals       => KeyCode::N,
+            Action::Area          => KeyCode::A,
+            Action::Stats         => KeyCode::I,
+            Action::Paint         => KeyCode::W,
+            Action::Magnet        => KeyCode::M,
+            Action::Merge         => KeyCode::J,
+            Action::MouseWind     => KeyCode::V,
+            Action::Perspective   => KeyCode::P,
+            Action::Reverse       => KeyCode::Backspace,
+            Action::Sag           => KeyCode::G,
+            Action::Record        => KeyCode::K,
+            Action::Deterministic => KeyCode::D,
+            Action::Shake         => KeyCode::X,
+            Action::Axes          => KeyCode::O,
+            Action::CloneCloth    => KeyCode::U,
+            Action::FreeParticles => KeyCode::Q,
+            Action::Decimate      => KeyCode::Z,
+            Action::AddRow        => KeyCode::E,
+            Action::PinNearest    => KeyCode::Y,
+            Action::SelfCollision => KeyCode::Key1,
+            Action::Boundary      => KeyCode::B,
+            Action::SaveConfig    => KeyCode::C,
+            Action::SaveState     => KeyCode::S,
+            Action::LoadState     => KeyCode::L,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Keymap {
+    keys: HashMap<Action, KeyCode>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            keys: Action::ALL.iter().map(|&action| (action, action.default_key())).collect(),
+        }
+    }
+}
+
+impl Keymap {
+    // Reads `action = "Key"` lines. Actions that aren't listed keep their default key, unless
+    // another action was bound to it, which leaves them unbound. Binding two actions to the
+    // same key is an error.
+    fn parse(source: &str) -> Result<Keymap, String> {
+        let bindings: HashMap<String, String> = toml::from_str(source).map_err(|e| e.to_string())?;
+        let mut keymap = Keymap::default();
+        let mut bound: HashMap<KeyCode, Action> = HashMap::new();
+
+        for (name, key_name) in &bindings {
+            let action = Action::ALL.iter()
+                .copied()
+                .find(|action| action.name() == name)
+                .ok_or_else(|| format!("unknown action \"{name}\""))?;
+            let key = parse_key(key_name).ok_or_else(|| format!("unknown key \"{key_name}\" for {name}"))?;
+
+            if let Some(other) = bound.insert(key, action) {
+                return Err(format!("{} and {name} are both bound to {key_name}", other.name()));
+            }
+            keymap.keys.insert(action, key);
+        }
+
+        keymap.keys.retain(|action, key| bound.get(key).is_none_or(|owner| owner == action));
+
+        Ok(keymap)
+    }
+
+    fn key(&self, action: Action) -> Option<KeyCode> {
+        self.keys.get(&action).copied()
+    }
+
+    fn pressed(&self, action: Action) -> bool {
+        self.key(action).is_some_and(is_key_pressed)
+    }
+
+    fn down(&self, action: Action) -> bool {
+        self.key(action).is_some_and(is_key_down)
+    }
+}
+
+fn load_keymap(path: &str) -> Result<Keymap, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    Keymap::parse(&source).map_err(|e| format!("{path}: {e}"))
+}
+
+// Caps the simulated time per frame, so a long stall doesn't trigger a burst of steps
+const MAX_ACCUMULATED_TIME: f32 = 0.25;
+
+// Frames left until input is accepted at frame number `frame`, 0 once the warm-up is over.
+fn warm_up_remaining(frame: usize, warm_up_frames: usize) -> usize {
+    warm_up_frames.saturating_sub(frame)
+}
+
+// Frame duration for a cap of `max_fps`. A cap that isn't a positive number is ignored.
+fn frame_target(max_fps: f32) -> Option<Duration> {
+    if max_fps > 0.0 {
+        Duration::try_from_secs_f32(1.0 / max_fps).ok()
+    } else {
+        None
+    }
+}
+
+// Time left to sleep to stretch a frame that took `elapsed` to `target`.
+fn frame_sleep(target: Duration, elapsed: Duration) -> Duration {
+    target.saturating_sub(elapsed)
+}
+
+const FRAME_HISTORY: usize = 120;
+// Number of frames the overrun warning stays visible
+const OVERRUN_FLASH_FRAMES: usize = 30;
+
+// Ring buffer of the most recent frame times, also counting the frames that went over budget.
+struct FrameTimes {
+    times: [f32; FRAME_HISTORY],
+    next: usize,
+    count: usize,
+    // Seconds per frame, 0 disables the overrun tracking
+    budget: f32,
+    overruns: usize,
+    frames_since_overrun: usize,
+}
+
+impl FrameTimes {
+    fn new(budget: f32) -> FrameTimes {
+        FrameTimes {
+            times: [0.0; FRAME_HISTORY],
+            next: 0,
+            count: 0,
+            budget,
+            overruns: 0,
+            frames_since_overrun: usize::MAX,
+        }
+    }
+
+    fn record(&mut self, frame_time: f32) {
+        self.times[self.next] = frame_time;
+        self.next = (self.next + 1) % FRAME_HISTORY;
+        self.count = (self.count + 1).min(FRAME_HISTORY);
+
+        if self.budget > 0.0 && frame_time > self.budget {
+            self.overruns += 1;
+            self.frames_since_overrun = 0;
+        } else {
+            self.frames_since_overrun = self.frames_since_overrun.saturating_add(1);
+        }
+    }
+
+    fn average(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        self.times[..self.count].iter().sum::<f32>() / self.count as f32
+    }
+
+    fn show_overrun_warning(&self) -> bool {
+        self.frames_since_overrun < OVERRUN_FLASH_FRAMES
+    }
+}
+
+// Everything the cloth renderer draws goes through this, so it can run against something
+// other than the screen.
+trait DrawSink {
+    fn line(&mut self, a: Vec3, b: Vec3, thickness: f32, color: Color);
+    fn circle(&mut self, center: Vec3, radius: f32, color: Color);
+    fn triangle(&mut self, a: Vec3, b: Vec3, c: Vec3, color: Color);
+}
+
+struct ScreenSink;
+
+// Software rasterizer drawing into an RGBA buffer, used for thumbnails.
+struct ImageSink {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 4]>,
+    // World to image transform, image = (world - offset) * scale
+    offset: Vec3,
+    scale: f32,
+}
+
+impl ImageSink {
+    fn new(width: u32, height: u32, background: Color) -> ImageSink {
+        ImageSink {
+            width,
+            height,
+            pixels: vec![background.into(); (width * height) as usize],
+            offset: Vec3::default(),
+            scale: 1.0,
+        }
+    }
+
+    // Zooms so that the rectangle from `min` to `max` fills the image, keeping a small margin.
+    fn fit(&mut self, min: Vec3, max: Vec3) {
+        const MARGIN: f32 = 8.0;
+
+        let size = max - min;
+        let inv_size = Vec3{x: size.x.max(1.0), y: size.y.max(1.0), z: 1.0}.recip();
+        let scale_x = (self.width as f32 - 2.0 * MARGIN) * inv_size.x;
+        let scale_y = (self.height as f32 - 2.0 * MARGIN) * inv_size.y;
+        self.scale = scale_x.min(scale_y);
+
+        // Center the rectangle
+        let center = (min + max) * 0.5;
+        let half_image = Vec3{x: self.width as f32 * 0.5, y: self.height as f32 * 0.5, z: 0.0};
+        self.offset = center - half_image * (1.0 / self.scale);
+    }
+
+    fn to_image(&self, p: Vec3) -> (f32, f32) {
+        ((p.x - self.offset.x) * self.scale, (p.y - self.offset.y) * self.scale)
+    }
+
+    fn plot(&mut self, x: i32, y: i32, color: Color) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            self.pixels[(y as u32 * self.width + x as u32) as usize] = color.into();
+        }
+    }
+
+    fn fill_disc(&mut self, x: f32, y: f32, radius: f32, color: Color) {
+        let r = radius.max(0.5);
+
+        for py in (y - r).floor() as i32..=(y + r).ceil() as i32 {
+            for px in (x - r).floor() as i32..=(x + r).ceil() as i32 {
+                let dx = px as f32 + 0.5 - x;
+                let dy = py as f32 + 0.5 - y;
+                if dx * dx + dy * dy <= r * r {
+                    self.plot(px, py, color);
+                }
+            }
+        }
+    }
+
+    fn save_png(&self, path: &str) -> Result<(), String> {
+        let bytes: Vec<u8> = self.pixels.iter().flatten().copied().collect();
+
+        image::save_buffer(path, &bytes, self.width, self.height, image::ColorType::Rgba8)
+            .map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+impl DrawSink for ImageSink {
+    fn line(&mut self, a: Vec3, b: Vec3, thickness: f32, color: Color) {
+        let (ax, ay) = self.to_image(a);
+        let (bx, by) = self.to_image(b);
+
+        // Stamp discs every half pixel along the line
+        let steps = ((bx - ax).abs().max((by - ay).abs()) * 2.0).ceil().max(1.0) as usize;
+        for i in 0..=steps {
+            let t = i as f32 / steps as f32;
+            self.fill_disc(ax + (bx - ax) * t, ay + (by - ay) * t, thickness * self.scale * 0.5, color);
+        }
+    }
+
+    fn circle(&mut self, center: Vec3, radius: f32, color: Color) {
+        let (x, y) = self.to_image(center);
+        self.fill_disc(x, y, radius * self.scale, color);
+    }
+
+    fn triangle(&mut self, a: Vec3, b: Vec3, c: Vec3, color: Color) {
+        let (ax, ay) = self.to_image(a);
+        let (bx, by) = self.to_image(b);
+        let (cx, cy) = self.to_image(c);
+
+        let area = (bx - ax) * (cy - ay) - (by - ay) * (cx - ax);
+        if area == 0.0 {
+            return;
+        }
+
+        let min_x = ax.min(bx).min(cx).floor() as i32;
+        let max_x = ax.max(bx).max(cx).ceil() as i32;
+        let min_y = ay.min(by).min(cy).floor() as i32;
+        let max_y = ay.max(by).max(cy).ceil() as i32;
+
+        for py in min_y..=max_y {
+            for px in min_x..=max_x {
+                let x = px as f32 + 0.5;
+                let y = py as f32 + 0.5;
+
+                // Barycentric weights, all share the sign of the area when inside
+                let w_a = ((bx - x) * (cy - y) - (by - y) * (cx - x)) / area;
+                let w_b = ((cx - x) * (ay - y) - (cy - y) * (ax - x)) / area;
+                let w_c = 1.0 - w_a - w_b;
+
+                if w_a >= 0.0 && w_b >= 0.0 && w_c >= 0.0 {
+                    self.plot(px, py, color);
+                }
+            }
+        }
+    }
+}
+
+impl DrawSink for ScreenSink {
+    fn line(&mut self, a: Vec3, b: Vec3, thickness: f32, color: Color) {
+        draw_line(a.x, a.y, b.x, b.y, thickness, color);
+    }
+
+    fn circle(&mut self, center: Vec3, radius: f32, color: Color) {
+        draw_circle(center.x, center.y, radius, color);
+    }
+
+    fn triangle(&mut self, a: Vec3, b: Vec3, c: Vec3, color: Color) {
+        draw_triangle(vec2(a.x, a.y), vec2(b.x, b.y), vec2(c.x, c.y), color);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenderMode {
+    // Constraints and particles
+    Lines,
+    // Filled triangles
+    Filled,
+    // Filled triangles with the constraints and particles drawn on top
+    FilledWireframe,
+    // Only the particles, drawn as dots of `RenderSettings::point_radius`
+    Points,
+    // Constraints colored by their stiffness
+    Stiffness,
+}
+
+impl RenderMode {
+    fn next(self) -> RenderMode {
+        match self {
+            RenderMode::Lines           => RenderMode::Filled,
+            RenderMode::Filled          => RenderMode::FilledWireframe,
+            RenderMode::FilledWireframe => RenderMode::Points,
+            RenderMode::Points          => RenderMode::Stiffness,
+            RenderMode::Stiffness       => RenderMode::Lines,
+        }
+    }
+}
+
+struct RenderSettings {
+    mode: RenderMode,
+    line_thickness: f32,
+    particle_radius: f32,
+    point_radius: f32,
+    // Scale line width with how stretched each constraint is
+    stretch_lines: bool,
+    // Constraints with a lower tear threshold than this were painted weak and get tinted
+    default_max_stretch: f32,
+    palette: Palette,
+    // Stiffness range covered by the heat map colors
+    min_stiffness: f32,
+    max_stiffness: f32,
+    // Debug lines along each triangle's normal
+    show_normals: bool,
+    normal_length: f32,
+    // Draw particles and lines smaller and dimmer the larger their z
+    perspective: bool,
+    perspective_depth: f32,
+    perspective_min_scale: f32,
+    perspective_max_scale: f32,
+    // World axes drawn from the origin
+    show_axes: bool,
+    axis_length: f32,
+    // Seconds over which added particles fade in
+    fade_duration: f32,
+    // Draw the constraints over the particles instead of under them
+    constraints_on_top: bool,
+}
+
+impl RenderSettings {
+    // Starts out in the lines mode with every toggle off
+    fn new(config: &Config, line_thickness: f32, particle_radius: f32) -> RenderSettings {
+        RenderSettings {
+            mode: RenderMode::Lines,
+            line_thickness,
+            particle_radius,
+            point_radius: config.point_radius,
+            stretch_lines: false,
+            default_max_stretch: config.max_stretch,
+            palette: config.palette.clone(),
+            min_stiffness: config.heat_map_min_stiffness,
+            max_stiffness: config.heat_map_max_stiffness,
+            show_normals: false,
+            normal_length: config.normal_length,
+            perspective: false,
+            perspective_depth: config.perspective_depth,
+            perspective_min_scale: config.perspective_min_scale,
+            perspective_max_scale: config.perspective_max_scale,
+            show_axes: false,
+            axis_length: config.axis_length,
+            fade_duration: config.fade_duration,
+            constraints_on_top: config.constraints_on_top,
+        }
+    }
+
+    fn depth_scale(&self, z: f32) -> f32 {
+        if !self.perspective {
+            return 1.0;
+        }
+
+        perspective_scale(z, self.perspective_depth, self.perspective_min_scale, self.perspective_max_scale)
+    }
+}
+
+// Fills the two triangles of every grid cell whose edges are still intact.
+fn draw_cloth_fill(cloth: &Cloth, sink: &mut impl DrawSink) {
+    let color = Color::new(0.25, 0.35, 0.6, 1.0);
+
+    for [a, b, c] in cloth.triangles() {
+        sink.triangle(cloth.pos[a], cloth.pos[b], cloth.pos[c], color);
+    }
+}
+
+// Short line from the center of a triangle along its normal.
+fn normal_line(a: Vec3, b: Vec3, c: Vec3, length: f32) -> (Vec3, Vec3) {
+    let center = (a + b + c) * (1.0 / 3.0);
+    let normal = (b - a).cross(c - a).normalized();
+
+    (center, center + normal * length)
+}
+
+// Ends of the world X, Y and Z axes drawn from the origin.
+fn axis_lines(length: f32) -> [(Vec3, Vec3); 3] {
+    let origin = Vec3::default();
+
+    [
+        (origin, Vec3{x: length, y: 0.0, z: 0.0}),
+        (origin, Vec3{x: 0.0, y: length, z: 0.0}),
+        (origin, Vec3{x: 0.0, y: 0.0, z: length}),
+    ]
+}
+
+// The 2D view drops z, which would collapse the Z axis to a point, so it's drawn at an angle.
+fn oblique(p: Vec3) -> Vec3 {
+    Vec3{x: p.x + p.z * 0.5, y: p.y + p.z * 0.5, z: p.z}
+}
+
+fn draw_axes(length: f32, sink: &mut impl DrawSink) {
+    for ((start, end), color) in axis_lines(length).into_iter().zip([RED, GREEN, BLUE]) {
+        sink.line(oblique(start), oblique(end), 2.0, color);
+    }
+}
+
+// A flat cloth has normals along z, which the 2D view drops, so the normals are drawn at an
+// angle like the Z axis. They still start from the face center where the cloth is drawn.
+fn draw_cloth_normals(cloth: &Cloth, length: f32, sink: &mut impl DrawSink) {
+    for [a, b, c] in cloth.triangles() {
+        let (start, end) = normal_line(cloth.pos[a], cloth.pos[b], cloth.pos[c], length);
+        sink.line(start, start + oblique(end - start), 1.0, GREEN);
+    }
+}
+
+fn draw_cloth_stiffness(cloth: &Cloth, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    for constraint in &cloth.constraints {
+        if !constraint.active {
+            continue;
+        }
+
+        let color = stiffness_color(constraint.stiffness, settings.min_stiffness, settings.max_stiffness);
+        sink.line(cloth.pos[constraint.idx_1], cloth.pos[constraint.idx_2], settings.line_thickness, color);
+    }
+}
+
+fn draw_cloth_lines(cloth: &Cloth, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    for constraint in &cloth.constraints {
+        if !constraint.active {
+            continue;
+        }
+
+        let p1 = cloth.pos[constraint.idx_1];
+        let p2 = cloth.pos[constraint.idx_2];
+
+        let thickness = if settings.stretch_lines {
+            stretch_thickness(settings.line_thickness, distance(p1, p2) / constraint.rest_length)
+        } else {
+            settings.line_thickness
+        };
+        // Painted weak spots are tinted so they can be seen while painting
+        let color = if constraint.max_stretch < settings.default_max_stretch {
+            MAROON
+        } else {
+            Color::from(settings.palette.constraint)
+        };
+        let scale = settings.depth_scale((p1.z + p2.z) / 2.0);
+        let age = cloth.age[constraint.idx_1].min(cloth.age[constraint.idx_2]);
+        let color = with_alpha(dim_color(color, scale), fade_alpha(age, settings.fade_duration));
+        sink.line(p1, p2, thickness * scale, color);
+    }
+}
+
+fn draw_cloth_shadow(cloth: &Cloth, shadow: &Shadow, ground_y: f32, sink: &mut impl DrawSink) {
+    let color = with_alpha(GRAY, shadow.opacity);
+
+    for (idx_1, idx_2, _, _) in cloth.constraints_with_lengths() {
+        let p1 = shadow_point(shadow, cloth.pos[idx_1], ground_y);
+        let p2 = shadow_point(shadow, cloth.pos[idx_2], ground_y);
+        sink.line(p1, p2, 3.0, color);
+    }
+}
+
+fn draw_cloth_tethers(cloth: &Cloth, sink: &mut impl DrawSink) {
+    for tether in &cloth.tethers {
+        sink.line(cloth.pos[tether.idx], tether.point, 1.0, DARKGRAY);
+        sink.circle(tether.point, 2.0, DARKGRAY);
+    }
+}
+
+fn draw_cloth_particles(cloth: &Cloth, radius: f32, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    let mut pinned = vec![false; cloth.pos.len()];
+    for constraint in &cloth.point_constraints {
+        pinned[constraint.idx] = true;
+    }
+
+    for (p, &pos) in cloth.pos.iter().enumerate() {
+        if !cloth.active[p] {
+            continue;
+        }
+
+        let held = cloth.held.is_some_and(|held| held.idx == p);
+        let color = settings.palette.particle_color(pinned[p], held, cloth.highlight.contains(&p));
+        let scale = settings.depth_scale(pos.z);
+        let color = with_alpha(dim_color(color, scale), fade_alpha(cloth.age[p], settings.fade_duration));
+        sink.circle(pos, radius * scale, color);
+    }
+}
+
+// The held particle's position and the point it's being pulled toward, None while not holding
+fn held_target_marker(cloth: &Cloth) -> Option<(Vec3, Vec3)> {
+    cloth.held.map(|held| (cloth.pos[held.idx], held.point))
+}
+
+fn draw_held_target(cloth: &Cloth, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    if let Some((particle, target)) = held_target_marker(cloth) {
+        let color = Color::from(settings.palette.held_target);
+        if settings.mode != RenderMode::Points {
+            sink.line(particle, target, 1.0, color);
+        }
+        sink.circle(target, settings.point_radius, color);
+    }
+}
+
+// Draws the particles and the lines, in the order given by `RenderSettings::constraints_on_top`.
+fn draw_cloth_wireframe<S: DrawSink>(cloth: &Cloth, settings: &RenderSettings, sink: &mut S, draw_lines: impl Fn(&Cloth, &RenderSettings, &mut S)) {
+    if !settings.constraints_on_top {
+        draw_lines(cloth, settings, sink);
+    }
+
+    draw_cloth_particles(cloth, settings.particle_radius, settings, sink);
+
+    if settings.constraints_on_top {
+        draw_lines(cloth, settings, sink);
+    }
+}
+
+fn draw_cloth_constraints(cloth: &Cloth, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    draw_cloth_tethers(cloth, sink);
+    draw_cloth_lines(cloth, settings, sink);
+}
+
+fn draw_cloth(cloth: &Cloth, settings: &RenderSettings, sink: &mut impl DrawSink) {
+    match settings.mode {
+        RenderMode::Lines => {
+            draw_cloth_wireframe(cloth, settings, sink, draw_cloth_constraints);
+        }
+        RenderMode::Filled => {
+            draw_cloth_fill(cloth, sink);
+        }
+        RenderMode::FilledWireframe => {
+            draw_cloth_fill(cloth, sink);
+            draw_cloth_wireframe(cloth, settings, sink, draw_cloth_constraints);
+        }
+        RenderMode::Points => {
+            draw_cloth_particles(cloth, settings.point_radius, settings, sink);
+        }
+        RenderMode::Stiffness => {
+            draw_cloth_wireframe(cloth, settings, sink, draw_cloth_stiffness);
+        }
+    }
+
+    // The points mode draws nothing but points, so it leaves out the normals and only marks
+    // the held target
+    let points_only = settings.mode == RenderMode::Points;
+    if settings.show_normals && !points_only {
+        draw_cloth_normals(cloth, settings.normal_length, sink);
+    }
+
+    draw_held_target(cloth, settings, sink);
+}
+
+const NUM_COLS:       usize = 10;
+const NUM_ROWS:       usize = 10;
+const START_DISTANCE: f32   = 20.0;
+// Density at which a particle inside an undeformed cloth weighs 1
+const DEFAULT_DENSITY: f32  = 1.0 / (START_DISTANCE * START_DISTANCE);
+
+// Random point within `radius` of the origin, using the global random number generator.
+fn jiggle_offset(radius: f32) -> Vec3 {
+    let angle = random_f32(0.0, std::f32::consts::TAU);
+    let length = radius * random_f32(0.0, 1.0).sqrt();
+
+    Vec3{x: angle.cos() * length, y: angle.sin() * length, z: 0.0}
+}
+
+// Velocity of `speed` in a random direction, using the global random number generator.
+fn shake_velocity(speed: f32) -> Vec3 {
+    let angle = random_f32(0.0, std::f32::consts::TAU);
+
+    Vec3{x: angle.cos(), y: angle.sin(), z: 0.0} * speed
+}
+
+// How far `p` lies outside of the box, 0 when it's inside.
+fn box_distance(p: Vec3, min: Vec3, max: Vec3) -> f32 {
+    (p - vclamp(p, min, max)).length()
+}
+
+// Stability probe: every step each pin is moved to a random point within `radius` of where it
+// started. Returns the furthest any particle got from the cloth's starting bounding box, which
+// is infinite if the simulation blew up.
+fn jiggle_stress(cloth: &mut Cloth, steps: usize, dt: f32, radius: f32) -> f32 {
+    let anchors: Vec<Vec3> = cloth.point_constraints.iter().map(|constraint| constraint.point).collect();
+
+    let mut min = Vec3{x: f32::MAX, y: f32::MAX, z: f32::MAX};
+    let mut max = Vec3{x: f32::MIN, y: f32::MIN, z: f32::MIN};
+    for &p in &cloth.pos {
+        min = Vec3{x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z)};
+        max = Vec3{x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z)};
+    }
+
+    let mut excursion: f32 = 0.0;
+    for _ in 0..steps {
+        for (constraint, &anchor) in cloth.point_constraints.iter_mut().zip(&anchors) {
+            constraint.point = anchor + jiggle_offset(radius);
+        }
+
+        cloth.step(dt);
+
+        for &p in &cloth.pos {
+            if !p.is_finite() {
+                return f32::INFINITY;
+            }
+            excursion = excursion.max(box_distance(p, min, max));
+        }
+    }
+
+    excursion
+}
+
+// Runs the pin jiggle stress test without opening a window and reports whether the cloth
+// stayed within `jiggle_limit` of where it started. Returns the process exit code.
+fn run_jiggle_stress(config: &Config, seed: u64) -> i32 {
+    rand::srand(seed);
+
+    // Hanging from its top corners only, the loosest way to hold it and the hardest to keep stable
+    let origin = Vec3{x: 400.0, y: 100.0, z: 0.0};
+    let built = ClothBuilder::new()
+        .origin(origin)
+        .max_stretch(config.max_stretch)
+        .stiffness(config.stiffness)
+        .pin_corners()
+        .build();
+    let mut cloth = match built {
+        Ok(cloth) => cloth,
+        Err(e) => {
+            eprintln!("Invalid cloth in config: {e}");
+            return 1;
+        }
+    };
+    cloth.set_compliance(config.compliance);
+    cloth.params.gravity = config.gravity;
+    cloth.params.num_iterations = config.num_iterations;
+    cloth.params.step_order = config.step_order;
+    cloth.params.tear_propagation = config.tear_propagation;
+
+    let excursion = jiggle_stress(&mut cloth, config.jiggle_steps, config.time_step, config.jiggle_radius);
+    if excursion <= config.jiggle_limit {
+        println!("Stable: particles stayed within {excursion} of the starting bounds over {} steps", config.jiggle_steps);
+        0
+    } else {
+        println!("Unstable: particles got {excursion} away from the starting bounds, the limit is {}", config.jiggle_limit);
+        1
+    }
+}
+
+fn config_or_default() -> Config {
+    match load_config(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) => {
+            if std::path::Path::new(CONFIG_PATH).exists() {
+                eprintln!("Failed to load config {e}");
+            }
+            Config::default()
+        }
+    }
+}
+
+fn main() {
+    // `cloth_sim --jiggle [seed]` runs the stress test headless instead of opening the window
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "--jiggle") {
+        let seed = args.get(2).and_then(|seed| seed.parse().ok()).unwrap_or(0);
+        std::process::exit(run_jiggle_stress(&config_or_default(), seed));
+    }
+
+    macroquad::Window::new("BasicShapes", run());
+}
+
+async fn run() {
+    const PARTICLE_RADIUS: f32   = 3.0;
+    const INTERSECT_THRESHOLD: f32 = PARTICLE_RADIUS + 3.0;
+    const LINE_THICKNESS:  f32   = 5.0;
+
+    // Last cursor position while shift-dragging the pins as a group
+    let mut pin_drag_mouse: Option<Vec3> = None;
+
+    let mut config = config_or_default();
+
+    let keymap = match load_keymap(KEYMAP_PATH) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            if std::path::Path::new(KEYMAP_PATH).exists() {
+                eprintln!("Failed to load keymap {e}");
+            }
+            Keymap::default()
+        }
+    };
+
+    // The scene file can be passed as the first argument, otherwise scene.toml is used if present
+    let scene_path = std::env::args().nth(1).unwrap_or_else(|| "scene.toml".to_string());
+    let scene = match load_scene(&scene_path) {
+        Ok(scene) => scene,
+        Err(e) => {
+            if std::path::Path::new(&scene_path).exists() {
+                eprintln!("Failed to load scene {e}");
+            }
+            Scene::default()
+        }
+    };
+
+    let mut paused = false;
+    let mut show_help = false;
+    let mut show_area = false;
+    let mut show_sag = false;
+    let mut show_stats = false;
+    let mut summary = StepSummary::default();
+    let mut render_settings = RenderSettings::new(&config, LINE_THICKNESS, PARTICLE_RADIUS);
+    let mut show_forces = false;
+    // While set, dragging paints weak areas that tear sooner instead of grabbing particles
+    let mut painting = false;
+    // (cloth index, particle index) of the particle shown in the trace panel
+    let mut traced: Option<(usize, usize)> = None;
+    let mut sim_time = 0.0;
+    let mut num_steps = 0;
+    // Set once the settle time has passed and the cloth has been pinned in place
+    let mut settled = false;
+    let mut history = History::new(config.history_length);
+    let mut break_log = config.break_log.as_deref().and_then(|path| match create_break_log(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!("Failed to create break log {e}");
+            None
+        }
+    });
+    // While set, the recorded history is played backward instead of simulating
+    let mut reversing = false;
+    // While set, constraints and pins are ignored and every particle moves on its own
+    let mut free_particles = false;
+    #[cfg(feature = "gltf")]
+    let mut recording: Option<GltfRecording> = None;
+
+    seed_random(config.deterministic, config.seed);
+
+    let mut cloths = Vec::new();
+    for i in 0..config.num_cloths {
+        // Leave a gap of two cells between neighbouring cloths
+        let offset = i as f32 * (NUM_COLS + 1) as f32 * START_DISTANCE;
+        let origin = Vec3{x: screen_width() / 2.0 + offset, y: screen_height() / 2.0, z: 0.0};
+
+        let mut builder = ClothBuilder::new()
+            .rows(NUM_ROWS)
+            .cols(NUM_COLS)
+            .spacing(START_DISTANCE)
+            .origin(origin)
+            .max_stretch(config.max_stretch)
+            .stiffness(config.stiffness);
+        if let Some(pins) = &config.pins {
+            builder = builder.pins(pins);
+        }
+        let mut cloth = builder.build().unwrap_or_else(|e| {
+            eprintln!("Invalid cloth in config, using the defaults: {e}");
+            ClothBuilder::new().origin(origin).build().expect("the default cloth is valid")
+        });
+        cloth.set_compliance(config.compliance);
+        cloth.set_pin_stiffness(config.pin_stiffness, config.pin_damping);
+        if let Err(e) = cloth.set_highlight(&config.highlight) {
+            eprintln!("Invalid highlight in config: {e}");
+        }
+        cloth.set_density(config.density.unwrap_or(DEFAULT_DENSITY));
+        // After the density so the crease doesn't count toward the cell areas
+        if let Some(fold) = &config.fold {
+            cloth.fold(fold);
+        }
+        cloth.set_radius(config.collision_radius);
+        let hem_radius = config.hem_collision_radius.unwrap_or(config.collision_radius);
+        cloth.weight_hem(config.hem_mass, hem_radius, config.hem_anchor_distance, config.hem_tether_length);
+
+        cloths.push(cloth);
+    }
+
+    // Scratch buffer for quadtree query results
+    let mut nearby: Vec<usize> = Vec::new();
+    let mut spatial_hash = SpatialHash::new(config.collision_radius * 2.0);
+
+    let solver = Solver::new(config.solver_threads).unwrap_or_else(|e| {
+        eprintln!("Failed to create solver thread pool {e}");
+        Solver::new(None).expect("the default solver can always be created")
+    });
+
+    let mut frame_times = FrameTimes::new(config.frame_budget);
+    // Real time that hasn't been simulated yet
+    let mut accumulator = 0.0;
+
+    let mut use_mouse_wind = false;
+    let mut wind = Vec3::default();
+    let mut last_mouse = mouse_position();
+
+    // Frames drawn so far
+    let mut frame: usize = 0;
+
+    // Top left corner of the view in the world, only moving with world bounds
+    let mut view_offset = Vec3::default();
+
+    let mut last_frame = Instant::now();
+    loop {
+        let frame_start = Instant::now();
+        let screen_size = Vec3{x: screen_width(), y: screen_height(), z: 0.0};
+
+        let mouse = mouse_position();
+        let mouse_delta = Vec3{x: mouse.0 - last_mouse.0, y: mouse.1 - last_mouse.1, z: 0.0};
+        last_mouse = mouse;
+
+        /**** Handle input ****/
+        // Input is ignored while the cloth settles during the warm-up
+        let warm_up_left = warm_up_remaining(frame, config.warm_up_frames);
+        let warming_up = warm_up_left > 0;
+        if !warming_up {
+            if keymap.pressed(Action::Pause) {
+                paused = !paused;
+            }
+
+            if keymap.pressed(Action::Help) {
+                show_help = !show_help;
+            }
+
+            if keymap.pressed(Action::MouseWind) {
+                use_mouse_wind = !use_mouse_wind;
+                wind = Vec3::default();
+            }
+
+            if use_mouse_wind {
+                wind = mouse_wind(wind, mouse_delta, get_frame_time(), config.mouse_wind_scale, config.mouse_wind_smoothing);
+            }
+
+            if keymap.pressed(Action::Reverse) {
+                reversing = !reversing;
+
+                // The most recent state is the one on screen, playing it would repeat a frame
+                if reversing {
+                    history.rewind();
+                }
+            }
+
+            if keymap.pressed(Action::Deterministic) {
+                toggle_deterministic(&mut config);
+                accumulator = 0.0;
+            }
+
+            if keymap.pressed(Action::AddRow) {
+                for cloth in &mut cloths {
+                    cloth.add_row();
+                }
+                history.clear();
+            }
+
+            if keymap.pressed(Action::Decimate) {
+                for cloth in &mut cloths {
+                    cloth.decimate();
+                }
+                history.clear();
+            }
+
+            if keymap.pressed(Action::CloneCloth) {
+                if let Some(last) = cloths.last() {
+                    let copy = last.cloned_with_offset(Vec3{x: config.clone_offset, y: 0.0, z: 0.0});
+                    cloths.push(copy);
+                    history.clear();
+                }
+            }
+
+            if keymap.pressed(Action::FreeParticles) {
+                free_particles = !free_particles;
+            }
+
+            if keymap.pressed(Action::SelfCollision) {
+                config.self_collision = !config.self_collision;
+            }
+
+            if keymap.pressed(Action::Shake) {
+                let velocity = shake_velocity(config.shake_speed);
+                for cloth in &mut cloths {
+                    cloth.shake(velocity, config.time_step);
+                }
+            }
+
+            if keymap.pressed(Action::Boundary) {
+                config.boundary_mode = config.boundary_mode.next();
+            }
+
+            if keymap.pressed(Action::Record) {
+                #[cfg(feature = "gltf")]
+                match recording.take() {
+                    Some(finished) => match finished.save(RECORDING_PATH) {
+                        Ok(()) => println!("Saved recording to {RECORDING_PATH}"),
+                        Err(e) => eprintln!("Failed to save recording {e}"),
+                    },
+                    None => recording = Some(GltfRecording::new(&cloths, config.time_step)),
+                }
+
+                #[cfg(not(feature = "gltf"))]
+                eprintln!("Recording to {RECORDING_PATH} needs the gltf feature, e.g. cargo run --features gltf");
+            }
+
+            if keymap.pressed(Action::SaveState) {
+                let result = save_state(STATE_PATH, &cloths)
+                    .and_then(|_| render_thumbnail(&cloths, &render_settings).save_png(&thumbnail_path(STATE_PATH)));
+
+                match result {
+                    Ok(()) => println!("Saved state to {STATE_PATH}"),
+                    Err(e) => eprintln!("Failed to save state {e}"),
+                }
+            }
+
+            if keymap.pressed(Action::LoadState) {
+                match load_state(STATE_PATH) {
+                    Ok(loaded) => {
+                        cloths = loaded;
+                        history.clear();
+                    }
+                    Err(e) => eprintln!("Failed to load state {e}"),
+                }
+            }
+
+            if keymap.pressed(Action::SaveConfig) {
+                match save_config(CONFIG_PATH, &config) {
+                    Ok(()) => println!("Saved config to {CONFIG_PATH}"),
+                    Err(e) => eprintln!("Failed to save config {e}"),
+                }
+            }
+
+            if keymap.pressed(Action::Area) {
+                show_area = !show_area;
+            }
+
+            if keymap.pressed(Action::Sag) {
+                show_sag = !show_sag;
+            }
+
+            if keymap.pressed(Action::StretchLines) {
+                render_settings.stretch_lines = !render_settings.stretch_lines;
+            }
+
+            if keymap.pressed(Action::RenderMode) {
+                render_settings.mode = render_settings.mode.next();
+            }
+
+            if keymap.pressed(Action::Forces) {
+                show_forces = !show_forces;
+            }
+
+            if keymap.pressed(Action::Normals) {
+                render_settings.show_normals = !render_settings.show_normals;
+            }
+
+            if keymap.pressed(Action::Axes) {
+                render_settings.show_axes = !render_settings.show_axes;
+            }
+
+            if keymap.pressed(Action::Perspective) {
+                render_settings.perspective = !render_settings.perspective;
+            }
+
+            if keymap.pressed(Action::Stats) {
+                show_stats = !show_stats;
+            }
+
+            if keymap.pressed(Action::Paint) {
+                painting = !painting;
+                for cloth in &mut cloths {
+                    cloth.held = None;
+                }
+            }
+
+            if keymap.pressed(Action::Merge) {
+                for cloth in &mut cloths {
+                    if let Some(held) = cloth.held {
+                        // Find the closest particle other than the held one
+                        cloth.active[held.idx] = false;
+                        let nearest = nearest_particle(&cloth.pos, &cloth.active, cloth.pos[held.idx]);
+                        cloth.active[held.idx] = true;
+
+                        if let Some((target, _)) = nearest {
+                            cloth.merge(target, held.idx);
+                        }
+                    }
+                }
+            }
+
+            if keymap.pressed(Action::PinNearest) {
+                if let Some((c, p)) = nearest_in_cloths(&cloths, mouse_world(view_offset)) {
+                    cloths[c].toggle_pin(p, config.pin_stiffness, config.pin_damping);
+                }
+            }
+
+            let holding_particle = cloths.iter().any(|cloth| cloth.held.is_some());
+
+            let shift_down = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+            let control_down = is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl);
+
+            if is_mouse_button_down(MouseButton::Left) && control_down {
+                // Select the particle to trace, or clear the selection when clicking next to the cloth
+                if is_mouse_button_pressed(MouseButton::Left) {
+                    let mouse_vec = mouse_world(view_offset);
+
+                    traced = None;
+                    let mut nearest: Option<f32> = None;
+                    for (c, cloth) in cloths.iter().enumerate() {
+                        if let Some((p, d)) = cloth.pick(mouse_vec, INTERSECT_THRESHOLD, config.use_quadtree, &mut nearby) {
+                            if nearest.is_none_or(|best| d < best) {
+                                nearest = Some(d);
+                                traced = Some((c, p));
+                            }
+                        }
+                    }
+                }
+            }
+            else if is_mouse_button_down(MouseButton::Left) && painting {
+                let mouse_vec = mouse_world(view_offset);
+
+                for cloth in &mut cloths {
+                    cloth.paint_tear_threshold(mouse_vec, config.paint_radius, config.paint_max_stretch);
+                }
+            }
+            else if is_mouse_button_down(MouseButton::Left) && shift_down && !holding_particle {
+                let mouse_vec = mouse_world(view_offset);
+
+                if let Some(last_mouse) = pin_drag_mouse {
+                    for cloth in &mut cloths {
+                        translate_pins(&mut cloth.point_constraints, mouse_vec - last_mouse);
+                    }
+                }
+                pin_drag_mouse = Some(mouse_vec);
+            }
+            else if is_mouse_button_down(MouseButton::Left) && pin_drag_mouse.is_none() {
+                let mouse_vec = mouse_world(view_offset);
+
+                if !holding_particle {
+                    // Pick the closest particle rather than the first one in range, so a fast
+                    // moving particle can be caught even when others are passing close by.
+                    let mut nearest: Option<(usize, usize, f32)> = None;
+                    for (c, cloth) in cloths.iter().enumerate() {
+                        if let Some((p, d)) = cloth.pick(mouse_vec, INTERSECT_THRESHOLD, config.use_quadtree, &mut nearby) {
+                            if nearest.is_none_or(|(_, _, best)| d < best) {
+                                nearest = Some((c, p, d));
+                            }
+                        }
+                    }
+
+                    if let Some((c, p, _)) = nearest {
+                        cloths[c].grab(p, mouse_vec);
+                    }
+                }
+                else {
+                    for cloth in &mut cloths {
+                        if let Some(held) = &mut cloth.held {
+                            held.point = mouse_vec;
+                        }
+                    }
+                }
+            }
+            else if !is_mouse_button_down(MouseButton::Left) {
+                for cloth in &mut cloths {
+                    cloth.held = None;
+                }
+                pin_drag_mouse = None;
+            }
+        }
+
+
+        /**** Update ****/
+        if !settled && !paused {
+            // Run as many fixed steps as fit in the time that has passed, so the simulation
+            // speed doesn't depend on the frame rate. Deterministic mode instead always runs one
+            // step per frame so the result doesn't depend on timing at all.
+            let frame_time = if config.deterministic { config.time_step } else { get_frame_time() };
+            accumulator = (accumulator + frame_time).min(MAX_ACCUMULATED_TIME);
+
+            let mut params = SimParams {
+                gravity: config.gravity,
+                num_iterations: config.num_iterations,
+                boundary_mode: config.boundary_mode,
+                step_order: config.step_order,
+                bounds_min: config.world_bounds.map_or(Vec3::default(), |bounds| bounds.min),
+                bounds_max: config.world_bounds.map_or(screen_size, |bounds| bounds.max),
+                use_quadtree: config.use_quadtree,
+                obstacles: scene.obstacles.clone(),
+                slow_regions: scene.slow_regions.clone(),
+                magnet: None,
+                wind,
+                tear_propagation: config.tear_propagation,
+                floor: config.floor,
+                relaxation_schedule: config.relaxation_schedule.clone(),
+                solve_constraints: !free_particles,
+                max_energy: config.max_energy,
+                collision_slop: config.collision_slop,
+                collision_bias: config.collision_bias,
+            };
+
+            if !warming_up && keymap.down(Action::Magnet) {
+                params.magnet = Some(Magnet {
+                    point: mouse_world(view_offset),
+                    radius: config.magnet_radius,
+                    strength: config.magnet_strength,
+                });
+            }
+
+            // Carries on forward from the oldest state once the history runs out
+            if reversing {
+                reversing = history.play_backward(&mut cloths, &mut accumulator, config.time_step);
+            }
+
+            while !reversing && accumulator >= config.time_step {
+                accumulator -= config.time_step;
+
+                params.gravity = ramped_gravity(config.gravity, num_steps, config.gravity_ramp_steps);
+                if let Some(pulse) = &config.gravity_pulse {
+                    params.gravity = params.gravity * pulse.factor(sim_time);
+                }
+                params.wind = wind + wind_at(&config.wind_profile, sim_time);
+                num_steps += 1;
+
+                summary = solver.step(&mut cloths, &params, config.time_step);
+
+                self_collide(&mut cloths, &mut spatial_hash, &config, free_particles);
+
+                history.record(&cloths);
+
+                if let Some(file) = &mut break_log {
+                    let time = sim_time + config.time_step;
+                    let lines: String = cloths.iter().enumerate()
+                        .flat_map(|(c, cloth)| cloth.breaks.iter().map(move |event| break_log_line(time, c, event) + "\n"))
+                        .collect();
+
+                    if let Err(e) = file.write_all(lines.as_bytes()) {
+                        eprintln!("Failed to write break log, disabling it: {e}");
+                        break_log = None;
+                    }
+                }
+                #[cfg(feature = "gltf")]
+                if let Some(Err(e)) = recording.as_mut().map(|recording| recording.record(&cloths)) {
+                    eprintln!("Stopped recording, {e}");
+                    if let Some(finished) = recording.take() {
+                        match finished.save(RECORDING_PATH) {
+                            Ok(()) => println!("Saved recording to {RECORDING_PATH}"),
+                            Err(e) => eprintln!("Failed to save recording {e}"),
+                        }
+                    }
+                }
+                sim_time += config.time_step;
+
+                if config.settle_time.is_some_and(|settle_time| sim_time >= settle_time) {
+                    for cloth in &mut cloths {
+                        pin_all(&cloth.pos, &mut cloth.point_constraints);
+                        cloth.held = None;
+                    }
+                    settled = true;
+                    break;
+                }
+            }
+        }
+
+        /**** Draw ****/
+        clear_background(BLACK);
+
+        if let Some(bounds) = &config.world_bounds {
+            if let Some(focus) = centroid(&cloths) {
+                view_offset = bounds.view_offset(focus, screen_size);
+            }
+            set_camera(&view_camera(view_offset, screen_size));
+            draw_rectangle_lines(bounds.min.x, bounds.min.y, bounds.max.x - bounds.min.x, bounds.max.y - bounds.min.y, 2.0, DARKGRAY);
+        }
+
+        for region in &scene.slow_regions {
+            draw_rectangle(region.min.x, region.min.y, region.max.x - region.min.x, region.max.y - region.min.y, Color::new(0.0, 0.3, 0.6, 0.25));
+        }
+
+        if let Some(floor) = &config.floor {
+            draw_line(view_offset.x, floor.y, view_offset.x + screen_size.x, floor.y, 2.0, DARKGRAY);
+        }
+
+        if let Some(shadow) = &config.shadow {
+            let ground_y = config.floor.map_or(view_offset.y + screen_size.y, |floor| floor.y);
+            for cloth in &cloths {
+                draw_cloth_shadow(cloth, shadow, ground_y, &mut ScreenSink);
+            }
+        }
+
+        for obstacle in &scene.obstacles {
+            draw_obstacle(obstacle);
+        }
+
+        if render_settings.show_axes {
+            draw_axes(render_settings.axis_length, &mut ScreenSink);
+        }
+
+        for cloth in &cloths {
+            draw_cloth(cloth, &render_settings, &mut ScreenSink);
+
+            // The forces that will be used in the next integration step
+            if show_forces {
+                for p in 0..cloth.pos.len() {
+                    let start = cloth.pos[p];
+                    let end = force_arrow_end(start, cloth.forces[p], config.force_arrow_scale);
+                    draw_line(start.x, start.y, end.x, end.y, 1.0, ORANGE);
+                }
+            }
+        }
+
+        if !warming_up && keymap.down(Action::Magnet) {
+            let mouse = mouse_world(view_offset);
+            draw_circle_lines(mouse.x, mouse.y, config.magnet_radius, 1.0, SKYBLUE);
+        }
+
+        if painting {
+            let mouse = mouse_world(view_offset);
+            draw_circle_lines(mouse.x, mouse.y, config.paint_radius, 1.0, MAROON);
+        }
+
+        // The cloths can change under the selection, e.g. when loading a state
+        let trace = traced
+            .and_then(|(c, p)| cloths.get(c).filter(|cloth| p < cloth.pos.len()).map(|cloth| cloth.trace(p, config.time_step)));
+
+        if let Some(trace) = &trace {
+            draw_circle_lines(trace.position.x, trace.position.y, PARTICLE_RADIUS + 4.0, 1.0, SKYBLUE);
+        }
+
+        set_default_camera();
+
+        draw_text(last_frame.elapsed().as_secs_f32().to_string().as_str(), 20.0, 20.0, 20.0, DARKGRAY);
+
+        let mode = if config.deterministic {
+            format!("deterministic, seed {}", config.seed)
+        } else {
+            "real time".to_string()
+        };
+        let self_collision = if config.self_collision { "on" } else { "off" };
+        let text = format!("{mode}, self-collision {self_collision}");
+        draw_text(text.as_str(), 20.0, screen_height() - 20.0, 20.0, DARKGRAY);
+
+        if frame_times.show_overrun_warning() {
+            let text = format!(
+                "frame budget overrun! {} so far, average {:.1} ms",
+                frame_times.overruns, frame_times.average() * 1000.0
+            );
+            draw_text(text.as_str(), 200.0, 20.0, 20.0, RED);
+        }
+
+        if show_stats {
+            let text = format!(
+                "residual: {:.3}  energy: {:.0}  max velocity: {:.1}  max move: {:.2}  torn: {}  self-constraints: {}  threads: {}",
+                summary.max_residual, summary.kinetic_energy, summary.max_velocity, summary.max_displacement, summary.broken_constraints,
+                summary.self_constraints, solver.num_threads()
+            );
+            draw_text(text.as_str(), 20.0, 60.0, 20.0, DARKGRAY);
+        }
+
+        if show_area {
+            let area: f32 = cloths.iter().map(|cloth| cloth.area()).sum();
+            let rest_area: f32 = cloths.iter().map(|cloth| cloth.rest_area()).sum();
+            let text = format!("area: {:.0} ({:.1}% of rest)", area, area / rest_area * 100.0);
+            draw_text(text.as_str(), 20.0, 40.0, 20.0, DARKGRAY);
+        }
+
+        if show_sag {
+            let sag = cloths.iter().filter_map(|cloth| cloth.sag()).reduce(f32::max);
+            let text = match sag {
+                Some(sag) => format!("sag: {sag:.1}"),
+                None      => "sag: no pins".to_string(),
+            };
+            draw_text(text.as_str(), 20.0, 80.0, 20.0, DARKGRAY);
+        }
+
+        if let (Some(trace), Some((c, p))) = (&trace, traced) {
+            let constraints: Vec<String> = trace.constraints.iter().map(|constraint| constraint.to_string()).collect();
+            let lines = [
+                format!("cloth {c}, particle {p}"),
+                format!("position: {:.1} {:.1} {:.1}", trace.position.x, trace.position.y, trace.position.z),
+                format!("velocity: {:.1} {:.1} {:.1}", trace.velocity.x, trace.velocity.y, trace.velocity.z),
+                format!("force: {:.1} {:.1} {:.1}", trace.force.x, trace.force.y, trace.force.z),
+                format!("constraints: {}", constraints.join(", ")),
+            ];
+
+            for (i, line) in lines.iter().enumerate() {
+                draw_text(line.as_str(), screen_width() - 320.0, 40.0 + i as f32 * 20.0, 20.0, SKYBLUE);
+            }
+        }
+
+        if warming_up {
+            let text = format!("warming up, {warm_up_left} frames");
+            draw_text(text.as_str(), screen_width() / 2.0 - 80.0, 40.0, 20.0, GRAY);
+        }
+
+        if show_help {
+            let mut y = 100.0;
+            for line in ["left mouse: grab a particle", "shift + left mouse: move all pins", "ctrl + left mouse: trace a particle"] {
+                draw_text(line, 20.0, y, 20.0, GRAY);
+                y += 20.0;
+            }
+
+            for action in Action::ALL {
+                let key = keymap.key(action).map_or("unbound".to_string(), |key| format!("{key:?}"));
+                let text = format!("{key}: {}", action.description());
+                draw_text(text.as_str(), 20.0, y, 20.0, GRAY);
+                y += 20.0;
+            }
+        }
+
+        // finish frame
+        frame_times.record(frame_start.elapsed().as_secs_f32());
+
+        if let Some(target) = config.max_fps.and_then(frame_target) {
+            sleep(frame_sleep(target, frame_start.elapsed()));
+        }
+
+        last_frame = Instant::now();
+        frame += 1;
+
+        next_frame().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    // The random number generator is global, so tests drawing from it take turns
+    static RANDOM: Mutex<()> = Mutex::new(());
+
+    fn lock_random() -> MutexGuard<'static, ()> {
+        RANDOM.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Cloth at rest on an exact grid, without the random jitter of `Cloth::new`
+    fn grid(num_rows: usize, num_cols: usize) -> Cloth {
+        let mut cloth = {
+            let _random = lock_random();
+            Cloth::new(num_rows, num_cols, START_DISTANCE, Vec3::default(), Config::default().max_stretch)
+        };
+
+        for p in 0..cloth.pos.len() {
+            let row = (p / num_cols) as f32;
+            let col = (p % num_cols) as f32;
+            cloth.pos[p] = Vec3{x: col * START_DISTANCE, y: row * START_DISTANCE, z: 0.0};
+        }
+        cloth.old_pos = cloth.pos.clone();
+
+        cloth
+    }
+
+    #[test]
+    fn grabbing_a_moving_particle_zeroes_its_velocity() {
+        let mut cloth = grid(3, 3);
+        let p = 7;
+        cloth.old_pos[p] = cloth.pos[p] - Vec3{x: 15.0, y: -40.0, z: 0.0};
+
+        let point = cloth.pos[p];
+        cloth.grab(p, point);
+
+        assert_eq!(cloth.pos[p] - cloth.old_pos[p], Vec3::default());
+        assert_eq!(cloth.held.map(|held| held.idx), Some(p));
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Draw {
+        Line { a: Vec3, b: Vec3, color: Color },
+        Circle { center: Vec3, color: Color },
+        Triangle,
+    }
+
+    // Records the draw calls instead of drawing
+    #[derive(Default)]
+    struct MockSink {
+        draws: Vec<Draw>,
+    }
+
+    impl MockSink {
+        fn count(&self, matches: impl Fn(&Draw) -> bool) -> usize {
+            self.draws.iter().filter(|draw| matches(draw)).count()
+        }
+    }
+
+    impl DrawSink for MockSink {
+        fn line(&mut self, a: Vec3, b: Vec3, _thickness: f32, color: Color) {
+            self.draws.push(Draw::Line { a, b, color });
+        }
+
+        fn circle(&mut self, center: Vec3, _radius: f32, color: Color) {
+            self.draws.push(Draw::Circle { center, color });
+        }
+
+        fn triangle(&mut self, _a: Vec3, _b: Vec3, _c: Vec3, _color: Color) {
+            self.draws.push(Draw::Triangle);
+        }
+    }
+
+    fn render_settings(mode: RenderMode) -> RenderSettings {
+        RenderSettings {
+            mode,
+            ..RenderSettings::new(&Config::default(), 1.0, 4.0)
+        }
+    }
+
+    #[test]
+    fn parses_one_obstacle_of_each_type() {
+        let scene = parse_scene(r#"
+            [[obstacles]]
+            type = "circle"
+            center = { x = 1.0, y = 2.0 }
+            radius = 3.0
+
+            [[obstacles]]
+            type = "sphere"
+            center = { x = 1.0, y = 2.0, z = 3.0 }
+            radius = 4.0
+
+            [[obstacles]]
+            type = "box"
+            min = { x = 0.0, y = 0.0 }
+            max = { x = 10.0, y = 5.0 }
+
+            [[obstacles]]
+            type = "gravity-well"
+            center = { x = 5.0, y = 6.0 }
+            strength = 100.0
+
+            [[obstacles]]
+            type = "wind-source"
+            position = { x = 0.0, y = 1.0 }
+            direction = { x = 1.0, y = 0.0 }
+            strength = 2.0
+            radius = 50.0
+        "#).unwrap();
+
+        assert_eq!(scene.obstacles, vec![
+            Obstacle::Circle { center: Vec3{x: 1.0, y: 2.0, z: 0.0}, radius: 3.0 },
+            Obstacle::Sphere { center: Vec3{x: 1.0, y: 2.0, z: 3.0}, radius: 4.0 },
+            Obstacle::Box { min: Vec3{x: 0.0, y: 0.0, z: 0.0}, max: Vec3{x: 10.0, y: 5.0, z: 0.0} },
+            Obstacle::GravityWell { center: Vec3{x: 5.0, y: 6.0, z: 0.0}, strength: 100.0 },
+            Obstacle::WindSource {
+                position: Vec3{x: 0.0, y: 1.0, z: 0.0},
+                direction: Vec3{x: 1.0, y: 0.0, z: 0.0},
+                strength: 2.0,
+                radius: 50.0,
+            },
+        ]);
+    }
+
+    #[test]
+    fn wrapping_past_the_right_edge_keeps_the_velocity() {
+        let min = Vec3{x: 0.0, y: 0.0, z: 0.0};
+        let max = Vec3{x: 800.0, y: 600.0, z: 0.0};
+        let mut pos = Vec3{x: 810.0, y: 300.0, z: 0.0};
+        let mut old_pos = Vec3{x: 804.0, y: 298.0, z: 0.0};
+
+        apply_boundary(BoundaryMode::Wrap, &mut pos, &mut old_pos, min, max);
+
+        assert_near(pos.x, 10.0);
+        assert_near(pos.y, 300.0);
+        assert_near(pos.x - old_pos.x, 6.0);
+        assert_near(pos.y - old_pos.y, 2.0);
+    }
+
+    #[test]
+    fn flat_cloth_has_its_rest_area() {
+        let cloth = grid(4, 6);
+        let cell_area = START_DISTANCE * START_DISTANCE;
+
+        assert_near(cloth.area(), 3.0 * 5.0 * cell_area);
+        assert_near(cloth.area(), cloth.rest_area());
+    }
+
+    #[test]
+    fn translating_pins_moves_every_anchor_by_the_delta() {
+        let mut cloth = grid(3, 5);
+        let before: Vec<Vec3> = cloth.point_constraints.iter().map(|constraint| constraint.point).collect();
+        let delta = Vec3{x: 12.5, y: -3.0, z: 1.0};
+
+        translate_pins(&mut cloth.point_constraints, delta);
+
+        assert_eq!(cloth.point_constraints.len(), before.len());
+        for (constraint, point) in cloth.point_constraints.iter().zip(before) {
+            assert_eq!(constraint.point, point + delta);
+        }
+    }
+
+    #[test]
+    fn slow_region_removes_more_velocity() {
+        let mut cloth = grid(1, 2);
+        let velocity = Vec3{x: 0.0, y: 5.0, z: 0.0};
+        for p in 0..2 {
+            cloth.old_pos[p] = cloth.pos[p] - velocity;
+        }
+        let region = SlowRegion {
+            min: Vec3{x: -5.0, y: -5.0, z: 0.0},
+            max: Vec3{x: 5.0, y: 5.0, z: 0.0},
+            damping: 0.5,
+        };
+
+        cloth.integrate(1.0 / 60.0, &[region]);
+
+        let inside = (cloth.pos[0] - cloth.old_pos[0]).length();
+        let outside = (cloth.pos[1] - cloth.old_pos[1]).length();
+        assert_near(inside, 2.5);
+        assert_near(outside, 5.0);
+    }
+
+    #[test]
+    fn stretched_constraints_get_thinner() {
+        assert_near(stretch_thickness(2.0, 1.0), 2.0);
+        assert_near(stretch_thickness(2.0, 2.0), 1.0);
+        assert_near(stretch_thickness(2.0, 0.5), 4.0);
+        // Clamped at the extremes
+        assert_near(stretch_thickness(2.0, 100.0), 0.4);
+        assert_near(stretch_thickness(2.0, 0.0), 6.0);
+    }
+
+    #[test]
+    fn settling_pins_every_particle_where_it_is() {
+        let mut cloth = grid(4, 4);
+        let dt = 1.0 / 60.0;
+        let settle_time = 0.5;
+
+        let mut sim_time = 0.0;
+        while sim_time < settle_time {
+            cloth.step(dt);
+            sim_time += dt;
+        }
+        pin_all(&cloth.pos, &mut cloth.point_constraints);
+
+        assert_eq!(cloth.point_constraints.len(), cloth.pos.len());
+        for (p, constraint) in cloth.point_constraints.iter().enumerate() {
+            assert_eq!(constraint.idx, p);
+            assert_eq!(constraint.point, cloth.pos[p]);
+        }
+    }
+
+    #[test]
+    fn dumped_config_round_trips() {
+        let config = Config {
+            num_iterations: 7,
+            gravity: Vec3{x: 1.0, y: 50.0, z: 0.0},
+            settle_time: Some(2.5),
+            pins: Some(vec![0, 4, 9]),
+            ..Config::default()
+        };
+
+        let source = toml::to_string(&config).unwrap();
+
+        assert_eq!(parse_config(&source).unwrap(), config);
+        assert_eq!(parse_config(&toml::to_string(&Config::default()).unwrap()).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn quadtree_query_returns_exactly_the_particles_in_range() {
+        // Enough scattered particles to split the tree several levels deep
+        let pos: Vec<Vec3> = (0..500)
+            .map(|i| Vec3{x: (i * 37 % 101) as f32 * 3.0, y: (i * 53 % 97) as f32 * 2.0, z: 0.0})
+            .collect();
+        let min = Vec3{x: 40.0, y: 25.0, z: 0.0};
+        let max = Vec3{x: 150.0, y: 90.0, z: 0.0};
+
+        let mut found = Vec::new();
+        Quadtree::build(&pos).query(&pos, min, max, &mut found);
+        found.sort();
+
+        let expected: Vec<usize> = (0..pos.len())
+            .filter(|&p| pos[p].x >= min.x && pos[p].x <= max.x && pos[p].y >= min.y && pos[p].y <= max.y)
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn particles_of_different_cloths_are_pushed_apart() {
+        let mut a = grid(1, 1);
+        let mut b = grid(1, 1);
+        a.set_radius(5.0);
+        b.set_radius(5.0);
+        b.pos[0] = Vec3{x: 4.0, y: 0.0, z: 0.0};
+        let mut cloths = vec![a, b];
+
+        collide_particles(&mut cloths, &mut SpatialHash::new(10.0));
+
+        assert_near(distance(cloths[0].pos[0], cloths[1].pos[0]), 10.0);
+        assert_near(cloths[0].pos[0].x, -3.0);
+        assert_near(cloths[1].pos[0].x, 7.0);
+    }
+
+    #[test]
+    fn spatial_hash_drops_cells_left_behind() {
+        let mut cloths = vec![grid(3, 3)];
+        let mut hash = SpatialHash::new(10.0);
+        hash.rebuild(&cloths);
+        let num_cells = hash.cells.len();
+
+        for _ in 0..20 {
+            for p in &mut cloths[0].pos {
+                p.x += 100.0;
+            }
+            hash.rebuild(&cloths);
+        }
+
+        assert_eq!(hash.cells.len(), num_cells);
+    }
+
+    #[test]
+    fn force_arrow_ends_at_the_scaled_force() {
+        let end = force_arrow_end(Vec3{x: 10.0, y: 20.0, z: 0.0}, Vec3{x: 30.0, y: -40.0, z: 0.0}, 0.5);
+
+        assert_eq!(end, Vec3{x: 25.0, y: 0.0, z: 0.0});
+    }
+
+    #[test]
+    fn painting_weakens_only_the_constraints_in_the_brush() {
+        let default_max_stretch = Config::default().max_stretch;
+        let mut cloth = grid(5, 5);
+        let center = Vec3::default();
+        let radius = 15.0;
+
+        cloth.paint_tear_threshold(center, radius, 1.2);
+
+        let mut painted = 0;
+        for constraint in &cloth.constraints {
+            let midpoint = (cloth.pos[constraint.idx_1] + cloth.pos[constraint.idx_2]) * 0.5;
+            if distance(midpoint, center) <= radius {
+                assert_eq!(constraint.max_stretch, 1.2);
+                painted += 1;
+            } else {
+                assert_eq!(constraint.max_stretch, default_max_stretch);
+            }
+        }
+        assert_eq!(painted, 2);
+    }
+
+    #[test]
+    fn filled_wireframe_draws_the_fill_and_the_lines() {
+        let cloth = grid(3, 3);
+        let mut sink = MockSink::default();
+
+        draw_cloth(&cloth, &render_settings(RenderMode::FilledWireframe), &mut sink);
+
+        assert!(sink.count(|draw| matches!(draw, Draw::Triangle)) > 0);
+        assert_eq!(sink.count(|draw| matches!(draw, Draw::Line { .. })), cloth.constraints.len());
+        assert_eq!(sink.count(|draw| matches!(draw, Draw::Circle { .. })), cloth.pos.len());
+
+        // The fill goes first so the wireframe ends up on top
+        let last_triangle = sink.draws.iter().rposition(|draw| matches!(draw, Draw::Triangle)).unwrap();
+        let first_line = sink.draws.iter().position(|draw| matches!(draw, Draw::Line { .. })).unwrap();
+        assert!(last_triangle < first_line);
+    }
+
+    #[test]
+    fn step_summary_reports_residual_and_energy() {
+        let dt = 1.0 / 60.0;
+
+        // Two free particles drifting together at 10 units per step, no gravity
+        let mut drifting = grid(1, 2);
+        drifting.point_constraints.clear();
+        drifting.params.gravity = Vec3::default();
+        for p in 0..2 {
+            drifting.old_pos[p] = drifting.pos[p] - Vec3{x: 10.0, y: 0.0, z: 0.0};
+        }
+        let summary = drifting.step(dt);
+
+        let speed = 10.0 / dt;
+        assert_near(summary.max_residual, 0.0);
+        assert_near(summary.kinetic_energy, 2.0 * 0.5 * speed * speed);
+        assert_near(summary.max_velocity, speed);
+        assert_eq!(summary.broken_constraints, 0);
+
+        // Two pins holding a constraint at 1.5 times its rest length
+        let mut stretched = grid(1, 2);
+        stretched.pos[1].x = 30.0;
+        stretched.old_pos[1].x = 30.0;
+        stretched.set_pins(&[0, 1]).unwrap();
+        let summary = stretched.step(dt);
+
+        assert_near(summary.max_residual, 0.5);
+        assert_near(summary.kinetic_energy, 0.0);
+    }
+
+    #[test]
+    fn hem_gets_the_heavier_mass() {
+        let mut cloth = grid(4, 3);
+
+        cloth.weight_hem(Some(5.0), 4.0, Some(30.0), 10.0);
+
+        for p in 0..cloth.pos.len() {
+            let expected = if p >= 9 { 5.0 } else { 1.0 };
+            assert_eq!(cloth.mass[p], expected, "particle {p}");
+        }
+        let tethered: Vec<usize> = cloth.tethers.iter().map(|tether| tether.idx).collect();
+        assert_eq!(tethered, vec![9, 10, 11]);
+    }
+
+    #[test]
+    fn step_order_decides_when_gravity_kicks_in() {
+        let dt = 1.0 / 60.0;
+        let gravity = Config::default().gravity;
+        let fall = |order: StepOrder| {
+            let mut cloth = grid(1, 1);
+            cloth.point_constraints.clear();
+            cloth.pos[0] = Vec3{x: 100.0, y: 100.0, z: 0.0};
+            cloth.old_pos[0] = cloth.pos[0];
+            cloth.params.step_order = order;
+            cloth.step(dt);
+            cloth.pos[0].y - 100.0
+        };
+
+        // Forces lag one step behind, so the first step doesn't move a particle at rest
+        assert_near(fall(StepOrder::IntegrateThenForces), 0.0);
+        assert_near(fall(StepOrder::ForcesThenIntegrate), gravity.y * dt * dt);
+    }
+
+    #[test]
+    fn magnet_pulls_particles_in_range_toward_the_cursor() {
+        let magnet = Magnet { point: Vec3{x: 100.0, y: 100.0, z: 0.0}, radius: 50.0, strength: 300.0 };
+
+        let force = magnet_force(&magnet, Vec3{x: 70.0, y: 60.0, z: 0.0});
+        assert_near(force.x, 0.6 * 300.0);
+        assert_near(force.y, 0.8 * 300.0);
+
+        assert_eq!(magnet_force(&magnet, Vec3{x: 0.0, y: 100.0, z: 0.0}), Vec3::default());
+    }
+
+    #[test]
+    fn out_of_range_pin_is_an_error() {
+        let mut cloth = grid(3, 3);
+
+        assert!(matches!(
+            cloth.set_pins(&[0, 9]),
+            Err(ClothError::PinOutOfRange { index: 9, num_particles: 9 })
+        ));
+        assert!(cloth.set_pins(&[0, 8]).is_ok());
+    }
+
+    #[test]
+    fn points_mode_draws_only_circles() {
+        let cloth = grid(3, 3);
+        let mut sink = MockSink::default();
+
+        draw_cloth(&cloth, &render_settings(RenderMode::Points), &mut sink);
+
+        assert_eq!(sink.count(|draw| matches!(draw, Draw::Circle { .. })), cloth.pos.len());
+        assert_eq!(sink.draws.len(), cloth.pos.len());
+    }
+
+    #[test]
+    fn points_mode_draws_no_lines_while_holding_with_normals_on() {
+        let mut cloth = grid(3, 3);
+        cloth.grab(4, Vec3{x: 70.0, y: 90.0, z: 0.0});
+        let settings = RenderSettings { show_normals: true, ..render_settings(RenderMode::Points) };
+        let mut sink = MockSink::default();
+
+        draw_cloth(&cloth, &settings, &mut sink);
+
+        assert_eq!(sink.count(|draw| !matches!(draw, Draw::Circle { .. })), 0);
+        // Every particle and the held target
+        assert_eq!(sink.draws.len(), cloth.pos.len() + 1);
+    }
+
+    #[test]
+    fn rebinding_pause_to_p_takes_the_key_from_perspective() {
+        let keymap = Keymap::parse(r#"pause = "P""#).unwrap();
+
+        assert_eq!(keymap.key(Action::Pause), Some(KeyCode::P));
+        assert_eq!(keymap.key(Action::Perspective), None);
+        assert_eq!(keymap.key(Action::Help), Some(KeyCode::H));
+    }
+
+    #[test]
+    fn binding_two_actions_to_one_key_is_an_error() {
+        assert!(Keymap::parse("pause = \"P\"\nhelp = \"P\"").is_err());
+        assert!(Keymap::parse("pause = \"P\"\nperspective = \"Space\"").is_ok());
+        assert!(Keymap::parse(r#"jump = "J""#).is_err());
+    }
+
+    #[test]
+    fn only_frames_over_budget_count_as_overruns() {
+        let mut frame_times = FrameTimes::new(0.02);
+
+        for frame_time in [0.01, 0.02, 0.025, 0.015, 0.1] {
+            frame_times.record(frame_time);
+        }
+        assert_eq!(frame_times.overruns, 2);
+        assert!(frame_times.show_overrun_warning());
+
+        let mut disabled = FrameTimes::new(0.0);
+        disabled.record(1.0);
+        assert_eq!(disabled.overruns, 0);
+    }
+
+    #[test]
+    fn recip_is_component_wise() {
+        assert_eq!(Vec3{x: 2.0, y: 4.0, z: 8.0}.recip(), Vec3{x: 0.5, y: 0.25, z: 0.125});
+    }
+
+    #[test]
+    fn vector_with_nan_is_not_finite() {
+        assert!(Vec3{x: 1.0, y: -2.0, z: 3.0}.is_finite());
+        assert!(!Vec3{x: 1.0, y: f32::NAN, z: 3.0}.is_finite());
+        assert!(!Vec3{x: f32::INFINITY, y: 0.0, z: 0.0}.is_finite());
+    }
+
+    #[test]
+    fn thumbnail_sits_next_to_the_state() {
+        assert_eq!(thumbnail_path("state.json"), "state.png");
+        assert_eq!(thumbnail_path("saves/run.1.json"), "saves/run.1.png");
+        assert_eq!(thumbnail_path("state"), "state.png");
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let cloths = vec![grid(3, 4), grid(2, 2)];
+        let source = serde_json::to_string(&cloths).unwrap();
+
+        let loaded = parse_state(&source).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].pos, cloths[0].pos);
+        assert_eq!(loaded[1].constraints.len(), cloths[1].constraints.len());
+    }
+
+    #[test]
+    fn malformed_states_are_rejected() {
+        let corrupt = |change: fn(&mut Cloth)| {
+            let mut cloth = grid(3, 3);
+            change(&mut cloth);
+            parse_state(&serde_json::to_string(&[cloth]).unwrap())
+        };
+
+        assert!(corrupt(|cloth| cloth.constraints[0].idx_2 = 9).is_err());
+        assert!(corrupt(|cloth| cloth.point_constraints[0].idx = 100).is_err());
+        assert!(corrupt(|cloth| cloth.tethers.push(Tether { idx: 9, point: Vec3::default(), length: 1.0 })).is_err());
+        assert!(corrupt(|cloth| { cloth.mass.pop(); }).is_err());
+        assert!(corrupt(|cloth| { cloth.old_pos.push(Vec3::default()); }).is_err());
+        assert!(corrupt(|cloth| cloth.num_rows = 4).is_err());
+        assert!(corrupt(|_| {}).is_ok());
+    }
+
+    #[test]
+    fn tearing_lowers_the_threshold_of_adjacent_constraints() {
+        let mut cloth = grid(3, 3);
+        for constraint in &mut cloth.constraints {
+            constraint.max_stretch = 3.0;
+        }
+        // Tears at its rest length
+        cloth.constraints[0].max_stretch = 0.5;
+        let (idx_1, idx_2) = (cloth.constraints[0].idx_1, cloth.constraints[0].idx_2);
+
+        assert_eq!(cloth.tear(0.5), 1);
+
+        for constraint in &cloth.constraints[1..] {
+            let adjacent = [idx_1, idx_2].contains(&constraint.idx_1) || [idx_1, idx_2].contains(&constraint.idx_2);
+            assert_near(constraint.max_stretch, if adjacent { 1.5 } else { 3.0 });
+        }
+        assert!(cloth.constraints[1..].iter().any(|constraint| constraint.max_stretch < 3.0));
+    }
+
+    #[test]
+    fn up_facing_face_has_a_vertical_normal_line() {
+        let a = Vec3{x: 0.0, y: 0.0, z: 0.0};
+        let b = Vec3{x: 0.0, y: 0.0, z: 3.0};
+        let c = Vec3{x: 3.0, y: 0.0, z: 0.0};
+
+        let (start, end) = normal_line(a, b, c, 2.0);
+
+        assert_near(start.x, 1.0);
+        assert_near(start.z, 1.0);
+        assert_near(end.x - start.x, 0.0);
+        assert_near(end.y - start.y, 2.0);
+        assert_near(end.z - start.z, 0.0);
+    }
+
+    #[test]
+    fn normals_of_a_flat_cloth_are_visible() {
+        let cloth = grid(3, 3);
+        let mut sink = MockSink::default();
+
+        draw_cloth_normals(&cloth, 5.0, &mut sink);
+
+        assert_eq!(sink.draws.len(), 8);
+        for draw in &sink.draws {
+            let Draw::Line { a, b, .. } = draw else { panic!("normals are lines") };
+            assert!(distance(Vec3{z: 0.0, ..*a}, Vec3{z: 0.0, ..*b}) > 1.0);
+        }
+    }
+
+    #[test]
+    fn stiffness_colors_run_from_blue_to_red() {
+        let soft = stiffness_color(0.2, 0.2, 0.8);
+        let stiff = stiffness_color(0.8, 0.2, 0.8);
+        let middle = stiffness_color(0.5, 0.2, 0.8);
+
+        assert_eq!((soft.r, soft.b), (0.0, 1.0));
+        assert_eq!((stiff.r, stiff.b), (1.0, 0.0));
+        assert_near(middle.r, 0.5);
+        assert_eq!(stiffness_color(2.0, 0.2, 0.8).r, 1.0);
+    }
+
+    #[test]
+    fn frame_sleep_fills_the_rest_of_the_frame() {
+        let target = frame_target(50.0).unwrap();
+
+        assert_eq!(target, Duration::from_millis(20));
+        assert_eq!(frame_sleep(target, Duration::from_millis(5)), Duration::from_millis(15));
+        assert_eq!(frame_sleep(target, Duration::from_millis(20)), Duration::ZERO);
+        assert_eq!(frame_sleep(target, Duration::from_millis(35)), Duration::ZERO);
+    }
+
+    #[test]
+    fn non_positive_frame_cap_is_ignored() {
+        assert_eq!(frame_target(0.0), None);
+        assert_eq!(frame_target(-30.0), None);
+        assert_eq!(frame_target(f32::NAN), None);
+    }
+
+    #[test]
+    fn pinned_particles_get_the_pinned_color() {
+        let cloth = grid(3, 3);
+        let settings = render_settings(RenderMode::Points);
+        let mut sink = MockSink::default();
+
+        draw_cloth(&cloth, &settings, &mut sink);
+
+        let pinned = cloth.point_constraints[0].idx;
+        let free = (0..cloth.pos.len()).find(|&p| cloth.point_constraints.iter().all(|pin| pin.idx != p)).unwrap();
+        let color_at = |p: usize| {
+            sink.draws.iter().find_map(|draw| match draw {
+                Draw::Circle { center, color } if *center == cloth.pos[p] => Some(*color),
+                _ => None,
+            })
+        };
+
+        assert_eq!(color_at(pinned), Some(Color::from(settings.palette.pinned)));
+        assert_eq!(color_at(free), Some(Color::from(settings.palette.particle)));
+    }
+
+    #[test]
+    fn merging_redirects_every_constraint_to_the_kept_particle() {
+        let mut cloth = grid(3, 3);
+        let (a, b) = (4, 5);
+        let references = |cloth: &Cloth, p: usize| {
+            cloth.constraints.iter().filter(|constraint| constraint.idx_1 == p || constraint.idx_2 == p).count()
+        };
+        let referencing_either = cloth.constraints.iter()
+            .filter(|constraint| [a, b].contains(&constraint.idx_1) || [a, b].contains(&constraint.idx_2))
+            .count();
+        let mass = cloth.mass[a] + cloth.mass[b];
+
+        cloth.merge(a, b);
+
+        assert_eq!(references(&cloth, b), 0);
+        assert_eq!(references(&cloth, a), referencing_either);
+        // The constraint that joined them now connects a to itself
+        assert!(cloth.constraints.iter().filter(|constraint| constraint.idx_1 == constraint.idx_2).all(|constraint| !constraint.active));
+        assert!(!cloth.active[b]);
+        assert_near(cloth.mass[a], mass);
+    }
+
+    #[test]
+    fn cursor_movement_blows_smoothed_wind() {
+        let delta = Vec3{x: 2.0, y: -1.0, z: 0.0};
+
+        let wind = mouse_wind(Vec3::default(), delta, 0.5, 0.5, 0.25);
+        assert_near(wind.x, 0.5);
+        assert_near(wind.y, -0.25);
+
+        let wind = mouse_wind(wind, delta, 0.5, 0.5, 0.25);
+        assert_near(wind.x, 0.875);
+        assert_near(wind.y, -0.4375);
+
+        assert_eq!(mouse_wind(wind, delta, 0.0, 0.5, 0.25), wind);
+    }
+
+    #[test]
+    fn perspective_scale_halves_every_depth_within_bounds() {
+        assert_near(perspective_scale(0.0, 100.0, 0.1, 4.0), 1.0);
+        assert_near(perspective_scale(100.0, 100.0, 0.1, 4.0), 0.5);
+        assert_near(perspective_scale(200.0, 100.0, 0.1, 4.0), 0.25);
+        assert_near(perspective_scale(-100.0, 100.0, 0.1, 4.0), 2.0);
+        assert!(perspective_scale(50.0, 100.0, 0.1, 4.0) < perspective_scale(10.0, 100.0, 0.1, 4.0));
+
+        assert_near(perspective_scale(1000.0, 100.0, 0.1, 4.0), 0.1);
+        assert_near(perspective_scale(-1000.0, 100.0, 0.1, 4.0), 4.0);
+        assert_near(perspective_scale(50.0, 0.0, 0.1, 4.0), 1.0);
+    }
+
+    #[test]
+    fn jiggle_is_reproducible_and_stays_bounded() {
+        let mut cloth = grid(6, 6);
+        let config = Config::default();
+        let _random = lock_random();
+
+        let offsets = |seed: u64| -> Vec<Vec3> {
+            rand::srand(seed);
+            (0..20).map(|_| jiggle_offset(config.jiggle_radius)).collect()
+        };
+        let first = offsets(42);
+        assert_eq!(first, offsets(42));
+        assert_ne!(first, offsets(43));
+        assert!(first.iter().all(|offset| offset.length() <= config.jiggle_radius + 1e-4));
+
+        rand::srand(42);
+        let excursion = jiggle_stress(&mut cloth, 500, config.time_step, config.jiggle_radius);
+        assert!(excursion <= config.jiggle_limit, "excursion {excursion}");
+    }
+
+    #[test]
+    fn gravity_ramps_from_zero_to_full() {
+        let gravity = Vec3{x: 0.0, y: 980.0, z: 0.0};
+
+        assert_eq!(ramped_gravity(gravity, 0, 60), Vec3::default());
+        assert_near(ramped_gravity(gravity, 30, 60).y, 490.0);
+        assert_eq!(ramped_gravity(gravity, 60, 60), gravity);
+        assert_eq!(ramped_gravity(gravity, 100, 60), gravity);
+        assert_eq!(ramped_gravity(gravity, 0, 0), gravity);
+    }
+
+    #[test]
+    fn constraint_lengths_match_the_particle_distances() {
+        let mut cloth = grid(3, 3);
+        cloth.pos[4].x += 5.0;
+        cloth.pos[8].z -= 3.0;
+        cloth.constraints[0].active = false;
+
+        let lengths: Vec<_> = cloth.constraints_with_lengths().collect();
+
+        assert_eq!(lengths.len(), cloth.constraints.len() - 1);
+        for (idx_1, idx_2, rest_length, length) in lengths {
+            assert_near(length, distance(cloth.pos[idx_1], cloth.pos[idx_2]));
+            assert_near(rest_length, START_DISTANCE);
+        }
+        assert!(cloth.constraints_with_lengths().any(|(.., length)| length > START_DISTANCE + 1.0));
+    }
+
+    #[test]
+    fn reverse_playback_visits_recorded_states_backward_once_per_step() {
+        let mut cloths = vec![grid(2, 2)];
+        let mut history = History::new(10);
+        for step in 0..4 {
+            cloths[0].pos[0].x = step as f32;
+            history.record(&cloths);
+        }
+
+        // Reversing starts by dropping the state on screen
+        history.rewind();
+        let mut accumulator = 2.5;
+        assert!(history.play_backward(&mut cloths, &mut accumulator, 1.0));
+        assert_eq!(cloths[0].pos[0].x, 1.0);
+        assert_near(accumulator, 0.5);
+
+        accumulator += 0.5;
+        assert!(history.play_backward(&mut cloths, &mut accumulator, 1.0));
+        assert_eq!(cloths[0].pos[0].x, 0.0);
+
+        accumulator = 1.0;
+        assert!(!history.play_backward(&mut cloths, &mut accumulator, 1.0));
+        assert_eq!(cloths[0].pos[0].x, 0.0);
+    }
+
+    #[test]
+    fn particles_are_separated_by_the_sum_of_their_radii() {
+        let mut a = grid(1, 1);
+        let mut b = grid(1, 1);
+        a.set_radius(3.0);
+        b.set_radius(5.0);
+        b.pos[0] = Vec3{x: 0.0, y: 2.0, z: 0.0};
+        let mut cloths = vec![a, b];
+
+        collide_particles(&mut cloths, &mut SpatialHash::new(4.0));
+
+        assert_near(distance(cloths[0].pos[0], cloths[1].pos[0]), 8.0);
+    }
+
+    #[test]
+    fn sag_is_the_drop_below_the_pins() {
+        // Hanging straight down from the pinned top row, at rest
+        let mut cloth = grid(3, 3);
+        cloth.point_constraints = (0..3)
+            .map(|p| PointConstraint { idx: p, point: cloth.pos[p], stiffness: None, damping: 0.0 })
+            .collect();
+
+        assert_near(cloth.sag().unwrap(), 2.0 * START_DISTANCE);
+
+        cloth.pos[7].y += 5.0;
+        assert_near(cloth.sag().unwrap(), 2.0 * START_DISTANCE + 5.0);
+
+        cloth.active[7] = false;
+        assert_near(cloth.sag().unwrap(), 2.0 * START_DISTANCE);
+
+        cloth.point_constraints.clear();
+        assert_eq!(cloth.sag(), None);
+    }
+
+    #[test]
+    fn highlighted_particles_get_the_highlight_color() {
+        let mut cloth = grid(3, 3);
+        cloth.point_constraints.clear();
+        cloth.set_highlight(&[2, 5]).unwrap();
+        let settings = render_settings(RenderMode::Points);
+        let mut sink = MockSink::default();
+
+        draw_cloth(&cloth, &settings, &mut sink);
+
+        for draw in &sink.draws {
+            let Draw::Circle { center, color } = draw else { continue };
+            let p = cloth.pos.iter().position(|p| p == center).unwrap();
+            let expected = if [2, 5].contains(&p) { settings.palette.highlight } else { settings.palette.particle };
+            assert_eq!(*color, Color::from(expected), "particle {p}");
+        }
+        assert!(cloth.set_highlight(&[9]).is_err());
+    }
+
+    #[test]
+    fn wind_profile_interpolates_between_keyframes() {
+        let keyframe = |time: f32, x: f32| WindKeyframe { time, wind: Vec3{x, y: 0.0, z: 0.0} };
+        let profile = [keyframe(1.0, 10.0), keyframe(3.0, 50.0), keyframe(4.0, 0.0)];
+
+        assert_eq!(wind_at(&profile, 1.0).x, 10.0);
+        assert_eq!(wind_at(&profile, 3.0).x, 50.0);
+        assert_eq!(wind_at(&profile, 4.0).x, 0.0);
+        assert_near(wind_at(&profile, 2.0).x, 30.0);
+        assert_near(wind_at(&profile, 3.75).x, 12.5);
+
+        // Held before the first and after the last keyframe
+        assert_eq!(wind_at(&profile, 0.0).x, 10.0);
+        assert_eq!(wind_at(&profile, 9.0).x, 0.0);
+        assert_eq!(wind_at(&[], 2.0), Vec3::default());
+    }
+
+    #[test]
+    fn self_constraints_are_counted_and_skipped() {
+        let mut cloth = grid(2, 2);
+        let mut constraint = cloth.constraints[0];
+        constraint.idx_2 = constraint.idx_1;
+        constraint.rest_length = 5.0;
+        cloth.constraints.push(constraint);
+        let before = cloth.pos.clone();
+
+        cloth.satisfy_constraints(1.0, 1.0 / 60.0);
+
+        assert_eq!(cloth.pos, before);
+        assert_eq!(cloth.step(1.0 / 60.0).self_constraints, 1);
+        assert!(cloth.pos.iter().all(|p| p.is_finite()));
+    }
+
+    #[test]
+    fn soft_pin_gives_way_in_proportion_to_the_load() {
+        let hang = |gravity: f32, stiffness: f32| {
+            let mut cloth = grid(1, 1);
+            let mass = cloth.mass[0];
+            cloth.point_constraints = vec![PointConstraint {
+                idx: 0,
+                point: cloth.pos[0],
+                stiffness: Some(stiffness * mass),
+                damping: 1.0,
+            }];
+            cloth.params.gravity = Vec3{x: 0.0, y: gravity, z: 0.0};
+
+            for _ in 0..600 {
+                cloth.step(1.0 / 60.0);
+            }
+            cloth.pos[0].y - cloth.point_constraints[0].point.y
+        };
+
+        // The spring settles where it balances the load, at load / stiffness
+        assert!((hang(1000.0, 100.0) - 10.0).abs() < 0.1);
+        assert!((hang(2000.0, 100.0) - 20.0).abs() < 0.2);
+        assert!((hang(1000.0, 200.0) - 5.0).abs() < 0.05);
+    }
+
+    #[cfg(feature = "gltf")]
+    #[test]
+    fn two_frame_recording_exports_two_morph_targets() {
+        let mut cloths = vec![grid(2, 2)];
+        let mut recording = GltfRecording::new(&cloths, 0.5);
+
+        recording.record(&cloths).unwrap();
+        for p in &mut cloths[0].pos {
+            p.y += 3.0;
+        }
+        recording.record(&cloths).unwrap();
+
+        let json = recording.to_json().unwrap();
+        let primitive = &json["meshes"][0]["primitives"][0];
+        let targets = primitive["targets"].as_array().unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(primitive["indices"], 0);
+        assert_eq!(json["accessors"][0]["count"], 6);
+        assert_eq!(json["accessors"][1]["count"], 4);
+
+        // glTF is y up, so moving down the screen is a negative delta
+        let second = &json["accessors"][targets[1]["POSITION"].as_u64().unwrap() as usize];
+        assert_eq!(second["min"][1], -3.0);
+        assert_eq!(second["max"][1], -3.0);
+
+        let sampler = &json["animations"][0]["samplers"][0];
+        let input = &json["accessors"][sampler["input"].as_u64().unwrap() as usize];
+        assert_eq!(input["count"], 2);
+        assert_eq!(input["max"][0], 0.5);
+        let output = &json["accessors"][sampler["output"].as_u64().unwrap() as usize];
+        assert_eq!(output["count"], 4);
+        assert!(output.get("bufferView").is_none());
+        assert_eq!(output["sparse"]["count"], 2);
+
+        // Sparse indices 0 and 3 of the 2x2 weights, then two ones
+        let view = &json["bufferViews"][output["sparse"]["indices"]["bufferView"].as_u64().unwrap() as usize];
+        assert_eq!(view["byteLength"], 16);
+        assert_eq!(output["sparse"]["values"]["byteOffset"], 8);
+    }
+
+    #[cfg(feature = "gltf")]
+    #[test]
+    fn recording_stops_once_full() {
+        let cloths = vec![grid(1, 1)];
+        let mut recording = GltfRecording::new(&cloths, 0.5);
+
+        for _ in 0..MAX_RECORDING_FRAMES {
+            recording.record(&cloths).unwrap();
+        }
+
+        assert!(recording.record(&cloths).is_err());
+        assert_eq!(recording.frames.len(), MAX_RECORDING_FRAMES);
+    }
+
+    #[cfg(feature = "gltf")]
+    #[test]
+    fn recording_refuses_a_change_in_particle_count() {
+        let mut cloths = vec![grid(2, 2)];
+        let mut recording = GltfRecording::new(&cloths, 0.5);
+        recording.record(&cloths).unwrap();
+
+        cloths.push(grid(1, 1));
+
+        assert!(recording.record(&cloths).is_err());
+        assert_eq!(recording.frames.len(), 1);
+    }
+
+    #[test]
+    fn torn_fragment_comes_to_rest_on_the_floor() {
+        let mut cloth = grid(2, 2);
+        cloth.point_constraints.clear();
+        for constraint in &mut cloth.constraints {
+            constraint.active = false;
+        }
+        cloth.old_pos[1].x -= 2.0;
+        cloth.params.gravity = Vec3{x: 0.0, y: 980.0, z: 0.0};
+        cloth.params.floor = Some(Floor { y: 100.0, friction: 0.5 });
+
+        for _ in 0..300 {
+            cloth.step(1.0 / 60.0);
+        }
+
+        for p in 0..cloth.pos.len() {
+            assert_near(cloth.pos[p].y, 100.0);
+            assert!(distance(cloth.pos[p], cloth.old_pos[p]) < 1e-3, "particle {p} is still moving");
+        }
+    }
+
+    #[test]
+    fn toggling_into_deterministic_mode_reseeds() {
+        let mut config = Config { deterministic: false, seed: 1234, ..Config::default() };
+        let _random = lock_random();
+
+        rand::srand(config.seed);
+        let expected: Vec<u32> = (0..5).map(|_| rand::rand()).collect();
+
+        rand::srand(99);
+        toggle_deterministic(&mut config);
+        assert!(config.deterministic);
+        assert_eq!((0..5).map(|_| rand::rand()).collect::<Vec<_>>(), expected);
+
+        toggle_deterministic(&mut config);
+        assert!(!config.deterministic);
+        toggle_deterministic(&mut config);
+        assert_eq!((0..5).map(|_| rand::rand()).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn masses_follow_the_surrounding_cell_areas() {
+        let lines = [0.0, 10.0, 30.0, 60.0];
+        let mut cloth = grid(4, 4);
+        for p in 0..16 {
+            cloth.pos[p] = Vec3{x: lines[p % 4], y: lines[p / 4], z: 0.0};
+        }
+
+        cloth.set_density(0.01);
+
+        // A quarter of each of the four cells around an interior particle
+        assert_near(cloth.mass[5], (100.0 + 200.0 + 200.0 + 400.0) / 4.0 * 0.01);
+        assert_near(cloth.mass[6], (200.0 + 300.0 + 400.0 + 600.0) / 4.0 * 0.01);
+        assert_near(cloth.mass[10], (400.0 + 600.0 + 600.0 + 900.0) / 4.0 * 0.01);
+        assert_near(cloth.mass[0], 100.0 / 4.0 * 0.01);
+    }
+
+    #[test]
+    fn default_density_weighs_interior_particles_as_1() {
+        let mut cloth = grid(3, 3);
+
+        cloth.set_density(DEFAULT_DENSITY);
+
+        assert_near(cloth.mass[4], 1.0);
+        assert_near(cloth.mass[1], 0.5);
+        assert_near(cloth.mass[0], 0.25);
+    }
+
+    #[test]
+    fn shaking_gives_every_free_particle_the_velocity() {
+        let mut cloth = grid(3, 3);
+        cloth.grab(4, cloth.pos[4]);
+        let dt = 1.0 / 60.0;
+        let velocity = {
+            let _random = lock_random();
+            shake_velocity(120.0)
+        };
+        assert_near(velocity.length(), 120.0);
+
+        cloth.shake(velocity, dt);
+
+        let fixed: Vec<usize> = cloth.point_constraints.iter().map(|pin| pin.idx).chain([4]).collect();
+        for p in 0..cloth.pos.len() {
+            let moved = (cloth.pos[p] - cloth.old_pos[p]) * (1.0 / dt);
+            let expected = if fixed.contains(&p) { Vec3::default() } else { velocity };
+            assert_near(moved.x, expected.x);
+            assert_near(moved.y, expected.y);
+        }
+    }
+
+    #[test]
+    fn axis_lines_run_from_the_origin_along_each_axis() {
+        let [x, y, z] = axis_lines(50.0);
+
+        assert_eq!(x, (Vec3::default(), Vec3{x: 50.0, y: 0.0, z: 0.0}));
+        assert_eq!(y, (Vec3::default(), Vec3{x: 0.0, y: 50.0, z: 0.0}));
+        assert_eq!(z, (Vec3::default(), Vec3{x: 0.0, y: 0.0, z: 50.0}));
+    }
+
+    #[test]
+    fn solver_uses_the_scheduled_relaxation_for_each_iteration() {
+        let schedule = [1.5, 0.5];
+        assert_eq!(relaxation_factor(&schedule, 0), 1.5);
+        assert_eq!(relaxation_factor(&schedule, 1), 0.5);
+        assert_eq!(relaxation_factor(&schedule, 5), 0.5);
+        assert_eq!(relaxation_factor(&[], 0), 1.0);
+
+        let mut cloth = grid(1, 2);
+        cloth.point_constraints.clear();
+        cloth.constraints[0].max_stretch = 10.0;
+        cloth.pos[1].x = 30.0;
+        cloth.old_pos = cloth.pos.clone();
+        cloth.params.gravity = Vec3::default();
+        cloth.params.num_iterations = 2;
+        cloth.params.relaxation_schedule = schedule.to_vec();
+
+        cloth.step(1.0 / 60.0);
+
+        // 30 over-relaxed by 1.5 down to 15, then under-relaxed by 0.5 back up to 17.5
+        assert_near(distance(cloth.pos[0], cloth.pos[1]), 17.5);
+    }
+
+    #[test]
+    fn clone_is_an_independent_shifted_copy() {
+        let mut cloth = grid(3, 3);
+        cloth.grab(4, cloth.pos[4]);
+        let offset = Vec3{x: 300.0, y: 0.0, z: 0.0};
+
+        let mut copy = cloth.cloned_with_offset(offset);
+
+        for p in 0..cloth.pos.len() {
+            assert_eq!(copy.pos[p], cloth.pos[p] + offset);
+            assert_eq!(copy.old_pos[p], cloth.old_pos[p] + offset);
+        }
+        for (pin, copied) in cloth.point_constraints.iter().zip(&copy.point_constraints) {
+            assert_eq!((copied.idx, copied.point), (pin.idx, pin.point + offset));
+        }
+        assert_eq!(copy.constraints.len(), cloth.constraints.len());
+        assert!(cloth.constraints.iter().zip(&copy.constraints)
+            .all(|(a, b)| (a.idx_1, a.idx_2, a.rest_length) == (b.idx_1, b.idx_2, b.rest_length)));
+        assert!(copy.held.is_none());
+
+        copy.constraints[0].active = false;
+        copy.pos[0].y += 10.0;
+        assert!(cloth.constraints[0].active);
+        assert_eq!(cloth.pos[0].y, 0.0);
+    }
+
+    #[test]
+    fn fold_displaces_only_the_far_side() {
+        let mut cloth = grid(4, 3);
+        cloth.fold(&Fold { axis: FoldAxis::Row, index: 1, depth: 15.0 });
+
+        for p in 0..cloth.pos.len() {
+            let expected = if p / 3 > 1 { 15.0 } else { 0.0 };
+            assert_eq!(cloth.pos[p].z, expected, "particle {p}");
+            assert_eq!(cloth.old_pos[p].z, expected);
+        }
+
+        let mut cloth = grid(3, 3);
+        cloth.fold(&Fold { axis: FoldAxis::Column, index: 0, depth: -5.0 });
+        assert_eq!(cloth.pos.iter().filter(|p| p.z == -5.0).count(), 6);
+        assert_eq!(cloth.pos[3].z, 0.0);
+    }
+
+    #[test]
+    fn crease_survives_the_window_boundary() {
+        let mut cloth = grid(4, 3);
+        cloth.fold(&Fold { axis: FoldAxis::Row, index: 1, depth: 15.0 });
+        cloth.params.boundary_mode = BoundaryMode::Clamp;
+
+        cloth.step(1.0 / 60.0);
+
+        assert!(cloth.pos[9..].iter().all(|p| p.z > 5.0));
+    }
+
+    #[test]
+    fn constraints_along_z_are_solved_to_their_rest_length() {
+        let mut cloth = grid(1, 2);
+        cloth.pos[1] = Vec3{x: 0.0, y: 0.0, z: 30.0};
+
+        cloth.satisfy_constraints(1.0, 1.0 / 60.0);
+
+        assert_near(distance(cloth.pos[0], cloth.pos[1]), START_DISTANCE);
+    }
+
+    #[test]
+    fn break_event_is_one_csv_line() {
+        let event = BreakEvent { constraint: 17, stretch: 2.25 };
+
+        let line = break_log_line(1.5, 2, &event);
+
+        assert_eq!(line, "1.5000,2,17,2.2500");
+        assert_eq!(line.split(',').count(), BREAK_LOG_HEADER.split(',').count());
+    }
+
+    #[test]
+    fn free_particles_drift_apart() {
+        let drift = |solve_constraints: bool| {
+            // Away from the window edges
+            let mut cloth = grid(1, 2).cloned_with_offset(Vec3{x: 100.0, y: 100.0, z: 0.0});
+            cloth.point_constraints.clear();
+            cloth.params.gravity = Vec3::default();
+            cloth.params.solve_constraints = solve_constraints;
+            // Pushes the two particles in opposite directions
+            cloth.old_pos[0].x += 1.0;
+            cloth.old_pos[1].x -= 1.0;
+
+            for _ in 0..30 {
+                cloth.step(1.0 / 60.0);
+            }
+            distance(cloth.pos[0], cloth.pos[1])
+        };
+
+        assert!(drift(false) > START_DISTANCE + 30.0);
+        assert_near(drift(true), START_DISTANCE);
+    }
+
+    #[test]
+    fn bouncy_soft_pin_overshoots_before_settling() {
+        let mut cloth = grid(1, 1).cloned_with_offset(Vec3{x: 100.0, y: 100.0, z: 0.0});
+        let anchor = cloth.pos[0];
+        let mass = cloth.mass[0];
+        cloth.point_constraints = vec![PointConstraint { idx: 0, point: anchor, stiffness: Some(100.0 * mass), damping: 0.2 }];
+        cloth.params.gravity = Vec3::default();
+        cloth.pos[0].y += 10.0;
+        cloth.old_pos[0] = cloth.pos[0];
+
+        let offsets: Vec<f32> = (0..600).map(|_| {
+            cloth.step(1.0 / 60.0);
+            cloth.pos[0].y - anchor.y
+        }).collect();
+
+        let overshoot = offsets.iter().copied().fold(f32::MAX, f32::min);
+        assert!(overshoot < -2.0, "overshoot {overshoot}");
+        assert!(offsets.last().unwrap().abs() < 0.01);
+    }
+
+    #[test]
+    fn decimating_a_5x5_grid_leaves_3x3() {
+        let mut cloth = grid(5, 5);
+
+        cloth.decimate();
+
+        assert_eq!((cloth.num_rows, cloth.num_cols), (3, 3));
+        assert_eq!(cloth.pos.len(), 9);
+        assert_eq!(cloth.constraints.len(), 12);
+        assert!(cloth.validate().is_ok());
+        for constraint in &cloth.constraints {
+            let (a, b) = (constraint.idx_1.min(constraint.idx_2), constraint.idx_1.max(constraint.idx_2));
+            // Neighbours along a row or a column of the new grid
+            assert!(b - a == 3 || (b - a == 1 && a % 3 != 2), "{a} to {b}");
+            assert_near(constraint.rest_length, 2.0 * START_DISTANCE);
+            assert_near(distance(cloth.pos[a], cloth.pos[b]), 2.0 * START_DISTANCE);
+        }
+    }
+
+    #[test]
+    fn decimating_keeps_the_masses_and_max_stretch() {
+        let mut cloth = grid(5, 5);
+        cloth.set_density(0.01);
+        for constraint in &mut cloth.constraints {
+            constraint.max_stretch = 1.7;
+        }
+        // As if merging had removed the constraint from 0 to 1
+        cloth.constraints.retain(|constraint| (constraint.idx_1, constraint.idx_2) != (0, 1));
+        let masses: Vec<f32> = [0, 2, 4, 10, 12, 14, 20, 22, 24].iter().map(|&p| cloth.mass[p]).collect();
+
+        cloth.decimate();
+
+        assert_eq!(cloth.mass, masses);
+        assert!(cloth.constraints.iter().all(|constraint| constraint.max_stretch == 1.7));
+        assert!(cloth.constraints.iter().any(|constraint| !constraint.active));
+    }
+
+    #[test]
+    fn energy_above_the_cap_is_scaled_down_to_it() {
+        let dt = 1.0 / 60.0;
+        let mut cloth = grid(3, 3);
+        for p in 0..cloth.pos.len() {
+            cloth.old_pos[p].x -= p as f32 * 0.5;
+        }
+        let energy = cloth.measure(dt).kinetic_energy;
+        let velocities: Vec<Vec3> = (0..9).map(|p| cloth.pos[p] - cloth.old_pos[p]).collect();
+
+        cloth.clamp_energy(energy / 4.0, dt);
+
+        assert!((cloth.measure(dt).kinetic_energy - energy / 4.0).abs() < energy * 1e-4);
+        for (p, velocity) in velocities.iter().enumerate() {
+            // A quarter of the energy is half the velocity, in the same direction
+            assert_near(cloth.pos[p].x - cloth.old_pos[p].x, velocity.x * 0.5);
+        }
+
+        let before = cloth.old_pos.clone();
+        cloth.clamp_energy(energy, dt);
+        assert_eq!(cloth.old_pos, before);
+    }
+
+    #[test]
+    fn held_target_is_marked_only_while_holding() {
+        let mut cloth = grid(3, 3);
+        let settings = render_settings(RenderMode::Lines);
+        let mut sink = MockSink::default();
+
+        assert!(held_target_marker(&cloth).is_none());
+        draw_held_target(&cloth, &settings, &mut sink);
+        assert!(sink.draws.is_empty());
+
+        let target = Vec3{x: 70.0, y: 90.0, z: 0.0};
+        cloth.grab(4, target);
+        assert_eq!(held_target_marker(&cloth), Some((cloth.pos[4], target)));
+
+        draw_held_target(&cloth, &settings, &mut sink);
+        let color = Color::from(settings.palette.held_target);
+        assert_eq!(sink.count(|draw| matches!(draw, Draw::Circle { center, color: c } if *center == target && *c == color)), 1);
+        assert_eq!(sink.count(|draw| matches!(draw, Draw::Line { a, b, .. } if *a == cloth.pos[4] && *b == target)), 1);
+    }
+
+    #[test]
+    fn input_is_ignored_only_during_the_warm_up() {
+        assert_eq!(warm_up_remaining(0, 60), 60);
+        assert_eq!(warm_up_remaining(59, 60), 1);
+        assert_eq!(warm_up_remaining(60, 60), 0);
+        assert_eq!(warm_up_remaining(500, 60), 0);
+        assert_eq!(warm_up_remaining(0, 0), 0);
+    }
+
+    #[test]
+    fn length_recip_is_one_over_the_length() {
+        assert_eq!(Vec3{x: 0.0, y: 4.0, z: 0.0}.length_recip(), 0.25);
+        assert_near(Vec3{x: 2.4, y: 0.0, z: 3.2}.length_recip(), 0.25);
+        assert_eq!(Vec3::default().length_recip(), f32::INFINITY);
+
+        assert_eq!(Vec3{x: 0.0, y: 0.0, z: 4.0}.normalized(), Vec3{x: 0.0, y: 0.0, z: 1.0});
+        assert_eq!(Vec3::default().normalized(), Vec3::default());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn solver_pool_has_the_configured_size() {
+        assert_eq!(Solver::new(Some(3)).unwrap().num_threads(), 3);
+        assert_eq!(Solver::new(Some(1)).unwrap().num_threads(), 1);
+        assert!(Solver::new(None).unwrap().num_threads() >= 1);
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    #[test]
+    fn solver_threads_need_the_parallel_feature() {
+        assert!(Solver::new(Some(3)).is_err());
+        assert_eq!(Solver::new(None).unwrap().num_threads(), 1);
+    }
+
+    #[test]
+    fn summary_reports_the_largest_move_of_a_step() {
+        let mut cloth = grid(3, 3).cloned_with_offset(Vec3{x: 100.0, y: 100.0, z: 0.0});
+        cloth.params.gravity = Vec3::default();
+        for pin in &mut cloth.point_constraints {
+            pin.point = cloth.pos[pin.idx];
+        }
+        let dt = 1.0 / 60.0;
+        assert_eq!(cloth.step(dt).max_displacement, 0.0);
+
+        // Yank the center particle 35 down in one step
+        cloth.grab(4, cloth.pos[4] + Vec3{x: 0.0, y: 35.0, z: 0.0});
+        let summary = cloth.step(dt);
+
+        assert_near(summary.max_displacement, 35.0);
+        assert_near(summary.max_velocity, 35.0 / dt);
+    }
+
+    #[test]
+    fn new_particles_fade_in_over_the_duration() {
+        assert_eq!(fade_alpha(0.0, 0.5), 0.0);
+        assert_near(fade_alpha(0.25, 0.5), 0.5);
+        assert_eq!(fade_alpha(0.5, 0.5), 1.0);
+        assert_eq!(fade_alpha(f32::INFINITY, 0.5), 1.0);
+        assert_eq!(fade_alpha(0.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn pin_nearest_pins_the_globally_nearest_particle() {
+        let mut cloths = vec![grid(3, 3), grid(3, 3).cloned_with_offset(Vec3{x: 200.0, y: 0.0, z: 0.0})];
+        for cloth in &mut cloths {
+            cloth.point_constraints.clear();
+        }
+        // Far from both cloths, but closest to the bottom right of the second
+        let cursor = Vec3{x: 500.0, y: 300.0, z: 0.0};
+
+        let (c, p) = nearest_in_cloths(&cloths, cursor).unwrap();
+        assert_eq!((c, p), (1, 8));
+
+        cloths[c].toggle_pin(p, Some(500.0), 0.3);
+        let pin = cloths[1].point_constraints[0];
+        assert_eq!((pin.idx, pin.point, pin.stiffness, pin.damping), (8, cloths[1].pos[8], Some(500.0), 0.3));
+        assert!(cloths[0].point_constraints.is_empty());
+
+        cloths[c].toggle_pin(p, Some(500.0), 0.3);
+        assert!(cloths[1].point_constraints.is_empty());
+    }
+
+    #[test]
+    fn draw_order_follows_constraints_on_top() {
+        let cloth = grid(2, 2);
+        let is_line = |draw: &Draw| matches!(draw, Draw::Line { .. });
+        let draws = |constraints_on_top: bool| {
+            let settings = RenderSettings { constraints_on_top, ..render_settings(RenderMode::Lines) };
+            let mut sink = MockSink::default();
+            draw_cloth(&cloth, &settings, &mut sink);
+            sink.draws
+        };
+
+        // Lines first and circles on top by default, the other way around otherwise
+        let below = draws(false);
+        let first_circle = below.iter().position(|draw| !is_line(draw)).unwrap();
+        assert!(first_circle > 0 && below[first_circle..].iter().all(|draw| !is_line(draw)));
+
+        let above = draws(true);
+        let first_line = above.iter().position(is_line).unwrap();
+        assert!(first_line > 0 && above[first_line..].iter().all(is_line));
+    }
+
+    #[test]
+    fn gravity_is_inverted_half_a_period_in() {
+        let square = GravityPulse { wave: PulseWave::Square, period: 2.0 };
+        assert_eq!(square.factor(0.0), 1.0);
+        assert_eq!(square.factor(0.9), 1.0);
+        assert_eq!(square.factor(1.0), -1.0);
+        assert_eq!(square.factor(2.0), 1.0);
+
+        let sine = GravityPulse { wave: PulseWave::Sine, period: 2.0 };
+        assert_near(sine.factor(0.0), 1.0);
+        assert_near(sine.factor(0.5), 0.0);
+        assert_near(sine.factor(1.0), -1.0);
+        assert!((0..200).all(|i| sine.factor(i as f32 * 0.01) >= -1.0));
+    }
+
+    #[test]
+    fn shallow_penetrations_are_ignored_and_deep_ones_pushed_past_the_surface() {
+        let circle = Obstacle::Circle { center: Vec3::default(), radius: 10.0 };
+        let (slop, bias) = (0.5, 0.25);
+
+        let shallow = Vec3{x: 9.7, y: 0.0, z: 0.0};
+        assert_eq!(collide_obstacle(&circle, shallow, slop, bias), shallow);
+
+        let deep = collide_obstacle(&circle, Vec3{x: 0.0, y: 7.0, z: 0.0}, slop, bias);
+        assert_near(deep.x, 0.0);
+        assert_near(deep.y, 10.25);
+
+        let square = Obstacle::Box { min: Vec3::default(), max: Vec3{x: 20.0, y: 20.0, z: 0.0} };
+        let shallow = Vec3{x: 0.3, y: 10.0, z: 0.0};
+        assert_eq!(collide_obstacle(&square, shallow, slop, bias), shallow);
+        assert_near(collide_obstacle(&square, Vec3{x: 3.0, y: 10.0, z: 0.0}, slop, bias).x, -0.25);
+    }
+
+    #[test]
+    fn builder_matches_direct_construction() {
+        let origin = Vec3{x: 50.0, y: 20.0, z: 0.0};
+        let (built, direct) = {
+            let _random = lock_random();
+            rand::srand(5);
+            let built = ClothBuilder::new().rows(4).cols(6).spacing(15.0).origin(origin).max_stretch(2.5).stiffness(0.5).pin_corners().build().unwrap();
+            rand::srand(5);
+            let mut direct = Cloth::new(4, 6, 15.0, origin, 2.5);
+            direct.set_stiffness(0.5);
+            direct.set_pins(&[0, 5]).unwrap();
+            (built, direct)
+        };
+
+        assert_eq!(built.pos, direct.pos);
+        assert_eq!((built.num_rows, built.num_cols, built.spacing), (4, 6, 15.0));
+        assert_eq!(built.constraints.len(), direct.constraints.len());
+        assert!(built.constraints.iter().zip(&direct.constraints)
+            .all(|(a, b)| (a.idx_1, a.idx_2, a.max_stretch, a.stiffness) == (b.idx_1, b.idx_2, b.max_stretch, b.stiffness)));
+        let pins: Vec<usize> = built.point_constraints.iter().map(|pin| pin.idx).collect();
+        assert_eq!(pins, [0, 5]);
+    }
+
+    #[test]
+    fn pinned_corners_follow_columns_set_afterwards() {
+        let built = {
+            let _random = lock_random();
+            ClothBuilder::new().pin_corners().rows(3).cols(6).build().unwrap()
+        };
+
+        let pins: Vec<usize> = built.point_constraints.iter().map(|pin| pin.idx).collect();
+        assert_eq!(pins, [0, 5]);
+    }
+
+    #[test]
+    fn built_cloths_fall_with_the_gravity_of_the_solver() {
+        let cloth = {
+            let _random = lock_random();
+            ClothBuilder::new().rows(3).cols(3).origin(Vec3{x: 100.0, y: 100.0, z: 0.0}).pins(&[]).build().unwrap()
+        };
+        let mut weightless = [cloth.clone()];
+        let mut falling = [cloth];
+        let solver = Solver::new(None).unwrap();
+        let gravity = 500.0;
+        let dt = 1.0 / 60.0;
+
+        for _ in 0..10 {
+            solver.step(&mut weightless, &SimParams { gravity: Vec3::default(), ..SimParams::default() }, dt);
+            solver.step(&mut falling, &SimParams { gravity: Vec3{x: 0.0, y: gravity, z: 0.0}, ..SimParams::default() }, dt);
+        }
+
+        // The constraints pull the particles equally both ways, so the cloth as a whole falls by
+        // the solver's gravity alone. By default the forces only apply from the second step on,
+        // which sums to 1 + 2 + ... + 9 steps of it.
+        let drop: f32 = (0..9).map(|p| falling[0].pos[p].y - weightless[0].pos[p].y).sum::<f32>() / 9.0;
+        assert!((drop - 45.0 * gravity * dt * dt).abs() < 1e-2, "{drop}");
+    }
+
+    #[test]
+    fn invalid_builder_parameters_are_build_errors() {
+        let _random = lock_random();
+        let builder = ClothBuilder::new().rows(3).cols(3);
+
+        assert_eq!(builder.clone().rows(0).build().err(), Some(ClothError::EmptyGrid { num_rows: 0, num_cols: 3 }));
+        assert_eq!(builder.clone().spacing(-1.0).build().err(), Some(ClothError::InvalidSpacing { spacing: -1.0 }));
+        assert_eq!(builder.clone().max_stretch(1.0).build().err(), Some(ClothError::InvalidMaxStretch { max_stretch: 1.0 }));
+        assert_eq!(builder.clone().stiffness(1.5).build().err(), Some(ClothError::InvalidStiffness { stiffness: 1.5 }));
+        assert_eq!(builder.clone().stiffness(-0.1).build().err(), Some(ClothError::InvalidStiffness { stiffness: -0.1 }));
+        assert_eq!(builder.clone().pins(&[9]).build().err(), Some(ClothError::PinOutOfRange { index: 9, num_particles: 9 }));
+        assert!(builder.stiffness(1.0).build().is_ok());
+    }
+
+    #[test]
+    fn particles_cast_their_shadow_onto_the_ground() {
+        let shadow = Shadow { offset: 20.0, flatten: 0.1, opacity: 0.4 };
+
+        // 200 above the ground drops to 20 above it, shifted sideways
+        let p = shadow_point(&shadow, Vec3{x: 100.0, y: 300.0, z: 7.0}, 500.0);
+        assert_eq!(p, Vec3{x: 120.0, y: 480.0, z: 0.0});
+
+        let below = shadow_point(&shadow, Vec3{x: 100.0, y: 600.0, z: 0.0}, 500.0);
+        assert_eq!(below.y, 500.0);
+    }
+
+    #[test]
+    fn large_world_bounds_keep_particles_past_the_window() {
+        let mut cloth = grid(2, 2);
+        cloth.point_constraints.clear();
+        cloth.params.gravity = Vec3::default();
+        cloth.params.boundary_mode = BoundaryMode::Clamp;
+        cloth.params.bounds_min = Vec3{x: -1000.0, y: -1000.0, z: 0.0};
+        cloth.params.bounds_max = Vec3{x: 3000.0, y: 3000.0, z: 0.0};
+        // Past the right and bottom edges of an 800x600 window
+        let beyond = Vec3{x: 1200.0, y: 900.0, z: 0.0};
+        let cloth = cloth.cloned_with_offset(beyond);
+
+        let mut stepped = cloth.clone();
+        stepped.step(1.0 / 60.0);
+
+        for p in 0..cloth.pos.len() {
+            assert_near(stepped.pos[p].x, cloth.pos[p].x);
+            assert_near(stepped.pos[p].y, cloth.pos[p].y);
+        }
+    }
+
+    #[test]
+    fn view_camera_keeps_y_growing_downward() {
+        let view_offset = Vec3{x: 300.0, y: 200.0, z: 0.0};
+        let screen_size = Vec3{x: 800.0, y: 600.0, z: 0.0};
+        let matrix = view_camera(view_offset, screen_size).matrix();
+
+        // The top left of the view is the top left of the screen, at (-1, 1) in clip space
+        let top_left = matrix.transform_point3(vec3(300.0, 200.0, 0.0));
+        let bottom_right = matrix.transform_point3(vec3(1100.0, 800.0, 0.0));
+        assert_near(top_left.x, -1.0);
+        assert_near(top_left.y, 1.0);
+        assert_near(bottom_right.x, 1.0);
+        assert_near(bottom_right.y, -1.0);
+    }
+
+    #[test]
+    fn self_collision_separates_particles_only_when_enabled() {
+        let mut a = grid(1, 1);
+        a.point_constraints.clear();
+        let mut b = a.cloned_with_offset(Vec3{x: 3.0, y: 0.0, z: 0.0});
+        a.set_radius(4.0);
+        b.set_radius(4.0);
+        let mut cloths = vec![a, b];
+        let mut hash = SpatialHash::new(10.0);
+        let mut config = Config { self_collision: false, ..Config::default() };
+
+        self_collide(&mut cloths, &mut hash, &config, false);
+        assert_near(distance(cloths[0].pos[0], cloths[1].pos[0]), 3.0);
+        assert!(hash.cells.is_empty());
+
+        config.self_collision = true;
+        self_collide(&mut cloths, &mut hash, &config, false);
+        assert_near(distance(cloths[0].pos[0], cloths[1].pos[0]), 8.0);
+    }
+
+    #[test]
+    fn compliance_softens_constraints_and_zero_is_rigid() {
+        // Length of a three particle chain hanging from its top at equilibrium
+        let hang = |compliance: Option<f32>| {
+            let mut cloth = grid(3, 1).cloned_with_offset(Vec3{x: 100.0, y: 100.0, z: 0.0});
+            cloth.point_constraints = vec![PointConstraint { idx: 0, point: cloth.pos[0], stiffness: None, damping: 0.0 }];
+            for constraint in &mut cloth.constraints {
+                constraint.max_stretch = 10.0;
+            }
+            cloth.set_compliance(compliance);
+            cloth.params.gravity = Config::default().gravity;
+
+            for _ in 0..600 {
+                cloth.step(1.0 / 60.0);
+            }
+            distance(cloth.pos[0], cloth.pos[2])
+        };
+
+        let rest = 2.0 * START_DISTANCE;
+        let pbd = hang(None);
+        let rigid = hang(Some(0.0));
+        let soft = hang(Some(1e-3));
+
+        assert!((rigid - pbd).abs() < 0.05 * rest, "rigid {rigid}, pbd {pbd}");
+        assert!((rigid - rest).abs() < 0.05 * rest, "rigid {rigid}");
+        // The top constraint carries two particles and the bottom one a single particle, each
+        // stretching by compliance times its load
+        let mass = grid(1, 1).mass[0];
+        let expected = 1e-3 * 3.0 * mass * Config::default().gravity.y;
+        assert!(((soft - rigid) - expected).abs() < 0.2 * expected, "soft {soft}, rigid {rigid}, expected {expected}");
+    }
+
+    #[test]
+    fn trace_lists_the_intact_constraints_of_the_particle() {
+        let mut cloth = grid(3, 3);
+        let p = 4;
+        let attached: Vec<usize> = (0..cloth.constraints.len())
+            .filter(|&c| cloth.constraints[c].idx_1 == p || cloth.constraints[c].idx_2 == p)
+            .collect();
+        assert_eq!(attached.len(), 4);
+        cloth.constraints[attached[1]].active = false;
+        cloth.old_pos[p].y -= 2.0;
+        cloth.forces[p] = Vec3{x: 1.0, y: 2.0, z: 3.0};
+
+        let trace = cloth.trace(p, 0.5);
+
+        let mut expected = attached.clone();
+        expected.remove(1);
+        assert_eq!(trace.constraints, expected);
+        assert_eq!(trace.position, cloth.pos[p]);
+        assert_eq!(trace.velocity, Vec3{x: 0.0, y: 4.0, z: 0.0});
+        assert_eq!(trace.force, Vec3{x: 1.0, y: 2.0, z: 3.0});
+    }
+
+    #[test]
+    fn added_rows_keep_the_max_stretch_and_mass_of_the_bottom_row() {
+        let mut cloth = grid(3, 3);
+        cloth.set_density(0.01);
+        for constraint in &mut cloth.constraints {
+            constraint.max_stretch = 1.7;
+        }
+        let num_constraints = cloth.constraints.len();
+
+        cloth.add_row();
+
+        assert_eq!(cloth.num_rows, 4);
+        assert!(cloth.validate().is_ok());
+        for col in 0..3 {
+            assert_near(cloth.mass[9 + col], cloth.mass[6 + col]);
+        }
+        assert_near(cloth.mass[9], 20.0 * 20.0 / 4.0 * 0.01);
+        assert_eq!(cloth.constraints.len(), num_constraints + 5);
+        assert!(cloth.constraints[num_constraints..].iter().all(|constraint| constraint.max_stretch == 1.7));
+    }
+}